@@ -18,4 +18,44 @@ pub enum CLOBError {
     DisallowedConfigValue,
     #[msg("Your size is not size. Try a bigger limit order")]
     MinLimitAmountNotMet,
+    #[msg("This limit order's `expiry_slot` has already passed")]
+    OrderAlreadyExpired,
+    #[msg("A pegged order's offset must keep its price above zero")]
+    PegOffsetOutOfRange,
+    #[msg("The TWAP oracle has no observation for this order to be pegged to")]
+    NoObservationToPegTo,
+    #[msg("Pegged orders are priced by the TWAP oracle and can't be given a new price")]
+    CannotRepricePeggedOrder,
+    #[msg("Updating this limit order to this price would cross the book")]
+    UpdateWouldCrossBook,
+    #[msg("This take order would have traded against the taker's own resting order")]
+    SelfTrade,
+    #[msg("This limit order's price is not a multiple of the order book's tick size")]
+    PriceNotOnTick,
+    #[msg("This limit order's amount is not a multiple of the order book's lot size")]
+    AmountNotOnLot,
+    #[msg("This limit order's price is too far from the TWAP oracle's last observation")]
+    PriceOutsideBand,
+    #[msg("Filling this take order would cost more than `max_in`")]
+    MaxInExceeded,
+    #[msg("This fill-or-kill take order could not be filled in full")]
+    FillOrKillNotFilled,
+    #[msg("This post-only limit order would cross the book")]
+    PostOnlyWouldCross,
+    #[msg("There is no room for another stop order on this order book")]
+    StopOrderListFull,
+    #[msg("The referrer's token account must hold the token that the taker fee is paid in")]
+    ReferrerMintMismatch,
+    #[msg("A market maker's authority can't be the default pubkey")]
+    InvalidMarketMakerAuthority,
+    #[msg("Another market maker was added too recently, wait for the cooldown")]
+    MarketMakerCooldown,
+    #[msg("The incinerator account is needed to burn the market maker deposit")]
+    MissingIncinerator,
+    #[msg("A limit order's price must be above zero")]
+    ZeroPrice,
+    #[msg("This market maker already has as many stop orders as it can")]
+    TooManyStopOrders,
+    #[msg("A stop order's max slippage must be below 100%")]
+    MaxSlippageOutOfRange,
 }
@@ -18,6 +18,22 @@ pub struct InitializeGlobalState<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct AllocateOrderBook<'info> {
+    pub base: Account<'info, Mint>,
+    pub quote: Account<'info, Mint>,
+    /// CHECK: only allocated here, `initialize_order_book` initializes it
+    #[account(
+        mut,
+        seeds = [b"order_book", base.key().as_ref(), quote.key().as_ref()],
+        bump
+    )]
+    pub order_book: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 pub struct InitializeOrderBook<'info> {
     pub base: Account<'info, Mint>,
@@ -37,11 +53,9 @@ pub struct InitializeOrderBook<'info> {
     )]
     pub quote_vault: Account<'info, TokenAccount>,
     #[account(
-        init,
+        zero,
         seeds = [b"order_book", base.key().as_ref(), quote.key().as_ref()],
-        bump,
-        payer = payer,
-        space = 8 + size_of::<OrderBook>()
+        bump
     )]
     pub order_book: AccountLoader<'info, OrderBook>,
     #[account(mut)]
@@ -96,6 +110,9 @@ pub struct AddMarketMaker<'info> {
     /// CHECK: no r/w, just lamport transfer
     #[account(mut)]
     pub admin: UncheckedAccount<'info>,
+    /// CHECK: no r/w, just lamport transfer
+    #[account(mut, address = solana_program::incinerator::ID)]
+    pub incinerator: Option<UncheckedAccount<'info>>,
     pub system_program: Program<'info, System>,
 }
 
@@ -131,11 +148,30 @@ pub struct WithdrawBalance<'info> {
     pub token_program: Program<'info, Token>,
 }
 
+#[derive(Accounts)]
+pub struct RemoveMarketMaker<'info> {
+    #[account(mut, has_one = base_vault, has_one = quote_vault)]
+    pub order_book: AccountLoader<'info, OrderBook>,
+    // receives the seat's deposit, if it has one
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub base_to: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub quote_to: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub base_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub quote_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
 #[derive(Accounts)]
 pub struct SubmitLimitOrder<'info> {
     #[account(mut)]
     pub order_book: AccountLoader<'info, OrderBook>,
     pub authority: Signer<'info>,
+    pub global_state: Account<'info, GlobalState>,
 }
 
 #[derive(Accounts)]
@@ -145,6 +181,28 @@ pub struct CancelLimitOrder<'info> {
     pub authority: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct CancelAllOrders<'info> {
+    #[account(mut)]
+    pub order_book: AccountLoader<'info, OrderBook>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct TransferMarketMaker<'info> {
+    #[account(mut)]
+    pub order_book: AccountLoader<'info, OrderBook>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ReplaceOrders<'info> {
+    #[account(mut)]
+    pub order_book: AccountLoader<'info, OrderBook>,
+    pub authority: Signer<'info>,
+    pub global_state: Account<'info, GlobalState>,
+}
+
 #[derive(Accounts)]
 pub struct UpdateLimitOrder<'info> {
     #[account(mut)]
@@ -154,7 +212,7 @@ pub struct UpdateLimitOrder<'info> {
 
 #[derive(Accounts)]
 pub struct SubmitTakeOrder<'info> {
-    #[account(mut)]
+    #[account(mut, has_one = base_vault, has_one = quote_vault)]
     pub order_book: AccountLoader<'info, OrderBook>,
     #[account(mut)]
     pub user_base_account: Account<'info, TokenAccount>,
@@ -167,6 +225,9 @@ pub struct SubmitTakeOrder<'info> {
     pub authority: Signer<'info>,
     pub global_state: Account<'info, GlobalState>,
     pub token_program: Program<'info, Token>,
+    /// Gets `referral_share_bps` of the taker fee, in the token it is paid in
+    #[account(mut)]
+    pub referrer: Option<Account<'info, TokenAccount>>,
 }
 
 #[derive(Accounts)]
@@ -1,6 +1,7 @@
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program;
 use solana_program::clock::Clock;
+use solana_program::entrypoint::MAX_PERMITTED_DATA_INCREASE;
 use std::mem::size_of;
 
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
@@ -22,6 +23,7 @@ pub const MAX_TAKER_FEE_BPS: u16 = 50; // 0.5%
                                        // These are not typos :)
 pub const MAX_MAX_OBSERVATION_CHANGE_PER_SLOT_BPS: u16 = 500; // 5%
 pub const MAX_MAX_OBSERVATION_CHANGE_PER_CHANGE_BPS: u16 = 1500; // 15%
+pub const MAX_REFERRAL_SHARE_BPS: u16 = 2500; // 25% of the taker fee
 
 #[program]
 pub mod clob {
@@ -35,16 +37,25 @@ pub mod clob {
 
         global_state.admin = admin;
         global_state.taker_fee_in_bps = 10;
+        global_state.maker_rebate_bps = 0;
+        global_state.referral_share_bps = 0;
         global_state.market_maker_burn_in_lamports = 1_000_000_000;
+        global_state.market_maker_cooldown_slots = 0;
+        global_state.market_maker_deposit_mode = MarketMakerDepositMode::PayAdmin;
 
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn update_global_state(
         ctx: Context<UpdateGlobalState>,
         new_admin: Option<Pubkey>,
         new_taker_fee_in_bps: Option<u16>,
         new_market_maker_burn_in_lamports: Option<u64>,
+        new_maker_rebate_bps: Option<u16>,
+        new_referral_share_bps: Option<u16>,
+        new_market_maker_cooldown_slots: Option<u64>,
+        new_market_maker_deposit_mode: Option<MarketMakerDepositMode>,
     ) -> Result<()> {
         let global_state = &mut ctx.accounts.global_state;
 
@@ -58,9 +69,82 @@ pub mod clob {
             );
             global_state.taker_fee_in_bps = new_taker_fee_in_bps;
         }
+        if let Some(new_maker_rebate_bps) = new_maker_rebate_bps {
+            global_state.maker_rebate_bps = new_maker_rebate_bps;
+        }
+        // The rebate is paid out of the taker fee, so it can't be bigger
+        require!(
+            global_state.maker_rebate_bps <= global_state.taker_fee_in_bps,
+            CLOBError::DisallowedConfigValue
+        );
+        if let Some(new_referral_share_bps) = new_referral_share_bps {
+            require!(
+                new_referral_share_bps <= MAX_REFERRAL_SHARE_BPS,
+                CLOBError::DisallowedConfigValue
+            );
+            global_state.referral_share_bps = new_referral_share_bps;
+        }
         if let Some(new_market_maker_burn_in_lamports) = new_market_maker_burn_in_lamports {
             global_state.market_maker_burn_in_lamports = new_market_maker_burn_in_lamports;
         }
+        if let Some(new_market_maker_cooldown_slots) = new_market_maker_cooldown_slots {
+            global_state.market_maker_cooldown_slots = new_market_maker_cooldown_slots;
+        }
+        if let Some(new_market_maker_deposit_mode) = new_market_maker_deposit_mode {
+            global_state.market_maker_deposit_mode = new_market_maker_deposit_mode;
+        }
+
+        Ok(())
+    }
+
+    /// Grows the order book account by as much as the runtime allows in one
+    /// instruction, creating it if it doesn't exist yet. An order book is too
+    /// large to create in a single instruction, so this needs to be called
+    /// until it reaches its full size before `initialize_order_book`.
+    pub fn allocate_order_book(ctx: Context<AllocateOrderBook>) -> Result<()> {
+        let order_book = ctx.accounts.order_book.to_account_info();
+        let payer = ctx.accounts.payer.to_account_info();
+        let system_program = ctx.accounts.system_program.to_account_info();
+
+        let current_len = order_book.data_len();
+        let new_len = std::cmp::min(
+            current_len + MAX_PERMITTED_DATA_INCREASE,
+            8 + size_of::<OrderBook>(),
+        );
+
+        let lamports_needed = Rent::get()?
+            .minimum_balance(new_len)
+            .saturating_sub(order_book.lamports());
+        if lamports_needed > 0 {
+            solana_program::program::invoke(
+                &solana_program::system_instruction::transfer(
+                    payer.key,
+                    order_book.key,
+                    lamports_needed,
+                ),
+                &[system_program.clone(), payer, order_book.clone()],
+            )?;
+        }
+
+        if order_book.owner == &solana_program::system_program::ID {
+            let base = ctx.accounts.base.key();
+            let quote = ctx.accounts.quote.key();
+            let pda_bump = *ctx.bumps.get("order_book").unwrap();
+            let seeds = &[b"order_book", base.as_ref(), quote.as_ref(), &[pda_bump]];
+
+            solana_program::program::invoke_signed(
+                &solana_program::system_instruction::allocate(order_book.key, new_len as u64),
+                &[system_program.clone(), order_book.clone()],
+                &[seeds],
+            )?;
+            solana_program::program::invoke_signed(
+                &solana_program::system_instruction::assign(order_book.key, ctx.program_id),
+                &[system_program, order_book],
+                &[seeds],
+            )?;
+        } else {
+            order_book.realloc(new_len, false)?;
+        }
 
         Ok(())
     }
@@ -91,22 +175,39 @@ pub mod clob {
         order_book.min_base_limit_amount = 1;
         order_book.min_quote_limit_amount = 1;
 
+        order_book.tick_size = 1;
+        order_book.base_lot_size = 1;
+        order_book.quote_lot_size = 1;
+
+        order_book.price_band_bps = 0;
+
+        order_book.taker_fee_override_in_bps = 0;
+        order_book.has_taker_fee_override = 0;
+
         order_book.base_fees_sweepable = 0;
         order_book.quote_fees_sweepable = 0;
 
+        order_book.last_market_maker_added_slot = 0;
+
         order_book.pda_bump = *ctx.bumps.get("order_book").unwrap();
 
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn update_order_book(
         ctx: Context<UpdateOrderBook>,
         new_max_observation_change_per_update_bps: u16,
         new_max_observation_change_per_slot_bps: u16,
         new_min_base_limit_amount: u64,
         new_min_quote_limit_amount: u64,
+        new_tick_size: u64,
+        new_base_lot_size: u64,
+        new_quote_lot_size: u64,
+        new_price_band_bps: u16,
+        new_taker_fee_override_in_bps: Option<u16>,
     ) -> Result<()> {
-        let mut order_book = ctx.accounts.order_book.load_init()?;
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
 
         require!(
             new_max_observation_change_per_update_bps <= MAX_MAX_OBSERVATION_CHANGE_PER_CHANGE_BPS,
@@ -134,6 +235,37 @@ pub mod clob {
         );
         order_book.min_quote_limit_amount = new_min_quote_limit_amount;
 
+        require!(new_tick_size > 0, CLOBError::DisallowedConfigValue);
+        order_book.tick_size = new_tick_size;
+
+        require!(new_base_lot_size > 0, CLOBError::DisallowedConfigValue);
+        order_book.base_lot_size = new_base_lot_size;
+
+        require!(new_quote_lot_size > 0, CLOBError::DisallowedConfigValue);
+        order_book.quote_lot_size = new_quote_lot_size;
+
+        require!(
+            new_price_band_bps <= MAX_BPS,
+            CLOBError::DisallowedConfigValue
+        );
+        order_book.price_band_bps = new_price_band_bps;
+
+        // `None` goes back to charging the global taker fee
+        match new_taker_fee_override_in_bps {
+            Some(new_taker_fee_override_in_bps) => {
+                require!(
+                    new_taker_fee_override_in_bps <= MAX_TAKER_FEE_BPS,
+                    CLOBError::DisallowedConfigValue
+                );
+                order_book.taker_fee_override_in_bps = new_taker_fee_override_in_bps;
+                order_book.has_taker_fee_override = 1;
+            }
+            None => {
+                order_book.taker_fee_override_in_bps = 0;
+                order_book.has_taker_fee_override = 0;
+            }
+        }
+
         Ok(())
     }
 
@@ -174,8 +306,6 @@ pub mod clob {
         )
     }
 
-    // TODO: make it so that after one market maker has been added, we have to
-    // wait a configurable cooldown before we can add another
     pub fn add_market_maker(
         ctx: Context<AddMarketMaker>,
         market_maker: Pubkey,
@@ -189,21 +319,56 @@ pub mod clob {
             CLOBError::IndexAlreadyTaken
         );
 
-        let from = ctx.accounts.payer.key();
-        let to = global_state.admin;
+        let slot = Clock::get()?.slot;
+
+        require!(
+            order_book.last_market_maker_added_slot == 0
+                || slot
+                    >= order_book
+                        .last_market_maker_added_slot
+                        .saturating_add(global_state.market_maker_cooldown_slots),
+            CLOBError::MarketMakerCooldown
+        );
+
         let lamports_to_burn = global_state.market_maker_burn_in_lamports;
 
+        let to = match global_state.market_maker_deposit_mode {
+            MarketMakerDepositMode::PayAdmin => ctx.accounts.admin.to_account_info(),
+            MarketMakerDepositMode::Escrow => ctx.accounts.order_book.to_account_info(),
+            MarketMakerDepositMode::Burn => ctx
+                .accounts
+                .incinerator
+                .as_ref()
+                .ok_or(CLOBError::MissingIncinerator)?
+                .to_account_info(),
+        };
+
+        let market_maker_to_add = &mut order_book.market_makers[index as usize];
+        market_maker_to_add.authority = market_maker;
+        market_maker_to_add.deposit_lamports =
+            if global_state.market_maker_deposit_mode == MarketMakerDepositMode::Escrow {
+                lamports_to_burn
+            } else {
+                0
+            };
+        order_book.last_market_maker_added_slot = slot;
+
+        // The transfer may need to borrow the order book
+        drop(order_book);
+
         solana_program::program::invoke(
-            &solana_program::system_instruction::transfer(&from, &to, lamports_to_burn),
+            &solana_program::system_instruction::transfer(
+                ctx.accounts.payer.key,
+                to.key,
+                lamports_to_burn,
+            ),
             &[
                 ctx.accounts.system_program.to_account_info(),
                 ctx.accounts.payer.to_account_info(),
-                ctx.accounts.admin.to_account_info(),
+                to,
             ],
         )?;
 
-        order_book.market_makers[index as usize].authority = market_maker;
-
         Ok(())
     }
 
@@ -294,6 +459,148 @@ pub mod clob {
         )
     }
 
+    /// Cancels all of a market maker's orders, pays out its balances, and
+    /// frees up its index for `add_market_maker` to reuse.
+    pub fn remove_market_maker(
+        ctx: Context<RemoveMarketMaker>,
+        market_maker_index: u32,
+    ) -> Result<()> {
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        order_book.update_twap_oracle()?;
+
+        let market_maker = order_book.market_makers[market_maker_index as usize];
+
+        require!(
+            market_maker.authority == ctx.accounts.authority.key(),
+            CLOBError::UnauthorizedMarketMaker
+        );
+
+        let (base_amount, quote_amount) = order_book.remove_market_maker(market_maker_index as u8);
+        let deposit_lamports = market_maker.deposit_lamports;
+
+        // Copy these onto the stack before we drop `order_book`
+        let base = order_book.base;
+        let quote = order_book.quote;
+        let pda_bump = order_book.pda_bump;
+
+        let seeds = &[b"order_book", base.as_ref(), quote.as_ref(), &[pda_bump]];
+
+        drop(order_book);
+
+        token_transfer_signed(
+            base_amount,
+            &ctx.accounts.token_program,
+            &ctx.accounts.base_vault,
+            &ctx.accounts.base_to,
+            &ctx.accounts.order_book,
+            seeds,
+        )?;
+
+        token_transfer_signed(
+            quote_amount,
+            &ctx.accounts.token_program,
+            &ctx.accounts.quote_vault,
+            &ctx.accounts.quote_to,
+            &ctx.accounts.order_book,
+            seeds,
+        )?;
+
+        // The order book is owned by this program, so we can move its
+        // lamports directly. This has to come after the CPIs, which would
+        // otherwise see lamports leave the order book without arriving at
+        // `authority`, which they aren't passed.
+        if deposit_lamports > 0 {
+            let order_book = ctx.accounts.order_book.to_account_info();
+            let authority = ctx.accounts.authority.to_account_info();
+
+            **order_book.try_borrow_mut_lamports()? -= deposit_lamports;
+            **authority.try_borrow_mut_lamports()? += deposit_lamports;
+        }
+
+        Ok(())
+    }
+
+    /// Hands a market maker's seat, along with its balances and orders, to
+    /// `new_authority`. If `require_acceptance` is set, `new_authority` only
+    /// gets the seat once it calls `accept_market_maker`, which guards
+    /// against typos.
+    pub fn transfer_market_maker(
+        ctx: Context<TransferMarketMaker>,
+        market_maker_index: u32,
+        new_authority: Pubkey,
+        require_acceptance: bool,
+    ) -> Result<()> {
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        let market_maker = &mut order_book.market_makers[market_maker_index as usize];
+
+        require!(
+            market_maker.authority == ctx.accounts.authority.key(),
+            CLOBError::UnauthorizedMarketMaker
+        );
+
+        // A seat with no authority counts as free
+        require!(
+            new_authority != Pubkey::default(),
+            CLOBError::InvalidMarketMakerAuthority
+        );
+
+        if require_acceptance {
+            market_maker.pending_authority = new_authority;
+        } else {
+            market_maker.authority = new_authority;
+            market_maker.pending_authority = Pubkey::default();
+            market_maker.delegate = Pubkey::default();
+        }
+
+        Ok(())
+    }
+
+    pub fn accept_market_maker(
+        ctx: Context<TransferMarketMaker>,
+        market_maker_index: u32,
+    ) -> Result<()> {
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        let market_maker = &mut order_book.market_makers[market_maker_index as usize];
+
+        require!(
+            market_maker.pending_authority != Pubkey::default()
+                && market_maker.pending_authority == ctx.accounts.authority.key(),
+            CLOBError::UnauthorizedMarketMaker
+        );
+
+        market_maker.authority = market_maker.pending_authority;
+        market_maker.pending_authority = Pubkey::default();
+        market_maker.delegate = Pubkey::default();
+
+        Ok(())
+    }
+
+    /// Lets `delegate` submit, update, and cancel orders on behalf of a
+    /// market maker, so that the authority key can be kept cold. Only the
+    /// authority can withdraw or change the seat. Pass `None` to revoke.
+    pub fn set_market_maker_delegate(
+        ctx: Context<TransferMarketMaker>,
+        market_maker_index: u32,
+        delegate: Option<Pubkey>,
+    ) -> Result<()> {
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        let market_maker = &mut order_book.market_makers[market_maker_index as usize];
+
+        require!(
+            market_maker.authority == ctx.accounts.authority.key(),
+            CLOBError::UnauthorizedMarketMaker
+        );
+
+        market_maker.delegate = delegate.unwrap_or_default();
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn submit_limit_order(
         ctx: Context<SubmitLimitOrder>,
         side: Side,
@@ -301,30 +608,87 @@ pub mod clob {
         price: u64,
         ref_id: u32,
         market_maker_index: u8,
-    ) -> Result<u8> {
+        expiry_slot: Option<u64>,
+        peg_offset_bps: Option<i16>,
+        order_type: OrderType,
+        hidden_amount: Option<u64>,
+    ) -> Result<Option<u8>> {
+        let global_state = &ctx.accounts.global_state;
         let mut order_book = ctx.accounts.order_book.load_mut()?;
 
         order_book.update_twap_oracle()?;
 
+        let fees = order_book.fees(global_state);
+
         let market_maker = order_book.market_makers[market_maker_index as usize];
 
         require!(
-            market_maker.authority == ctx.accounts.authority.key(),
+            market_maker.can_trade(ctx.accounts.authority.key()),
             CLOBError::UnauthorizedMarketMaker
         );
 
-        let min_amount = match side {
-            Side::Buy => order_book.min_quote_limit_amount,
-            Side::Sell => order_book.min_base_limit_amount,
+        let order = LimitOrderParams {
+            side,
+            amount_in,
+            price,
+            ref_id,
+            expiry_slot,
+            peg_offset_bps,
+            order_type,
+            hidden_amount,
         };
-        require!(amount_in >= min_amount, CLOBError::MinLimitAmountNotMet);
 
-        let (order_list, makers) = order_book.order_list(side);
+        Ok(order_book.submit_limit_order(order, market_maker_index, fees, Clock::get()?.slot)?)
+    }
+
+    #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+    pub enum LimitOrderOutcome {
+        /// The order, or what remained of it after crossing the book, is
+        /// resting at this index of its side's order list.
+        Placed { order_index: u8 },
+        /// The order crossed the book and was completely filled.
+        Filled,
+        /// The order was rejected with this `CLOBError` code.
+        Rejected { error_code: u32 },
+    }
+
+    pub fn submit_limit_orders(
+        ctx: Context<SubmitLimitOrder>,
+        market_maker_index: u8,
+        orders: Vec<LimitOrderParams>,
+    ) -> Result<Vec<LimitOrderOutcome>> {
+        let global_state = &ctx.accounts.global_state;
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        order_book.update_twap_oracle()?;
 
-        let order_idx =
-            order_list.insert_order(amount_in, price, ref_id, market_maker_index, makers);
+        let fees = order_book.fees(global_state);
+
+        let market_maker = order_book.market_makers[market_maker_index as usize];
+
+        require!(
+            market_maker.can_trade(ctx.accounts.authority.key()),
+            CLOBError::UnauthorizedMarketMaker
+        );
 
-        order_idx.ok_or_else(|| error!(CLOBError::InferiorPrice))
+        let slot = Clock::get()?.slot;
+
+        // A rejected order leaves the book untouched, so the rest of the batch
+        // can still be placed.
+        let outcomes = orders
+            .into_iter()
+            .map(|order| {
+                match order_book.submit_limit_order(order, market_maker_index, fees, slot) {
+                    Ok(Some(order_index)) => LimitOrderOutcome::Placed { order_index },
+                    Ok(None) => LimitOrderOutcome::Filled,
+                    Err(err) => LimitOrderOutcome::Rejected {
+                        error_code: err.into(),
+                    },
+                }
+            })
+            .collect();
+
+        Ok(outcomes)
     }
 
     pub fn cancel_limit_order(
@@ -340,7 +704,7 @@ pub mod clob {
         let market_maker = order_book.market_makers[market_maker_index as usize];
 
         require!(
-            market_maker.authority == ctx.accounts.authority.key(),
+            market_maker.can_trade(ctx.accounts.authority.key()),
             CLOBError::UnauthorizedMarketMaker
         );
 
@@ -358,12 +722,259 @@ pub mod clob {
         Ok(())
     }
 
+    pub fn cancel_all_orders(
+        ctx: Context<CancelAllOrders>,
+        market_maker_index: u8,
+        side: Option<Side>,
+    ) -> Result<u16> {
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        order_book.update_twap_oracle()?;
+
+        let market_maker = order_book.market_makers[market_maker_index as usize];
+
+        require!(
+            market_maker.can_trade(ctx.accounts.authority.key()),
+            CLOBError::UnauthorizedMarketMaker
+        );
+
+        let sides: &[Side] = match side {
+            Some(Side::Buy) => &[Side::Buy],
+            Some(Side::Sell) => &[Side::Sell],
+            None => &[Side::Buy, Side::Sell],
+        };
+
+        // Each side can hold `BOOK_DEPTH` orders, which together don't fit in a `u8`
+        let mut num_cancelled: u16 = 0;
+        for side in sides {
+            let (order_list, makers) = order_book.order_list(*side);
+
+            num_cancelled += order_list.delete_maker_orders(market_maker_index, makers) as u16;
+        }
+
+        Ok(num_cancelled)
+    }
+
+    /// Cancels all of a maker's orders and submits `orders` in their place. If
+    /// any of the new orders is rejected, none of this takes effect, so takers
+    /// never see a half-updated set of quotes.
+    pub fn replace_orders(
+        ctx: Context<ReplaceOrders>,
+        market_maker_index: u8,
+        orders: Vec<LimitOrderParams>,
+    ) -> Result<Vec<Option<u8>>> {
+        let global_state = &ctx.accounts.global_state;
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        order_book.update_twap_oracle()?;
+
+        let fees = order_book.fees(global_state);
+
+        let market_maker = order_book.market_makers[market_maker_index as usize];
+
+        require!(
+            market_maker.can_trade(ctx.accounts.authority.key()),
+            CLOBError::UnauthorizedMarketMaker
+        );
+
+        for side in [Side::Buy, Side::Sell] {
+            let (order_list, makers) = order_book.order_list(side);
+
+            order_list.delete_maker_orders(market_maker_index, makers);
+        }
+
+        let slot = Clock::get()?.slot;
+
+        let mut order_indices = Vec::with_capacity(orders.len());
+        for order in orders {
+            order_indices.push(order_book.submit_limit_order(
+                order,
+                market_maker_index,
+                fees,
+                slot,
+            )?);
+        }
+
+        Ok(order_indices)
+    }
+
+    pub fn update_limit_order(
+        ctx: Context<UpdateLimitOrder>,
+        side: Side,
+        order_index: u8,
+        market_maker_index: u8,
+        new_amount_in: Option<u64>,
+        new_price: Option<u64>,
+    ) -> Result<()> {
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        order_book.update_twap_oracle()?;
+
+        let market_maker = order_book.market_makers[market_maker_index as usize];
+
+        require!(
+            market_maker.can_trade(ctx.accounts.authority.key()),
+            CLOBError::UnauthorizedMarketMaker
+        );
+
+        let (order_list, _) = order_book.order_list(side);
+
+        let order = order_list.orders[order_index as usize];
+
+        require!(
+            order.market_maker_index == market_maker_index,
+            CLOBError::UnauthorizedMarketMaker
+        );
+
+        let (min_amount, lot_size) = order_book.min_amount_and_lot_size(side);
+        let new_amount_in = match new_amount_in {
+            Some(new_amount_in) => {
+                require!(new_amount_in % lot_size == 0, CLOBError::AmountNotOnLot);
+                new_amount_in
+            }
+            None => order.amount_in,
+        };
+        let new_price = match new_price {
+            Some(new_price) => {
+                require!(!order.is_pegged(), CLOBError::CannotRepricePeggedOrder);
+                require!(new_price > 0, CLOBError::ZeroPrice);
+                require!(
+                    new_price % order_book.tick_size == 0,
+                    CLOBError::PriceNotOnTick
+                );
+                require!(
+                    order_book.is_within_price_band(new_price),
+                    CLOBError::PriceOutsideBand
+                );
+                new_price
+            }
+            None => order.price,
+        };
+
+        let balance = match side {
+            Side::Buy => market_maker.quote_balance,
+            Side::Sell => market_maker.base_balance,
+        };
+        require!(new_amount_in >= min_amount, CLOBError::MinLimitAmountNotMet);
+        require!(
+            new_amount_in <= order.amount_in + balance,
+            CLOBError::InsufficientBalance
+        );
+
+        // Unlike new limit orders, updated ones are never filled against the book
+        let (opposing_order_list, _) = order_book.opposing_order_list(side);
+        require!(
+            !opposing_order_list.is_crossed_by(new_price),
+            CLOBError::UpdateWouldCrossBook
+        );
+
+        let (order_list, makers) = order_book.order_list(side);
+
+        order_list.update_order(order_index, new_amount_in, new_price, makers);
+
+        Ok(())
+    }
+
+    /// Places a stop order that rests off the book until the TWAP oracle's
+    /// observation reaches `trigger_price`, at which point it becomes a limit
+    /// order at `limit_price`, or a take if there is no `limit_price` that
+    /// fills within `max_slippage_bps` of the observation.
+    #[allow(clippy::too_many_arguments)]
+    pub fn submit_stop_order(
+        ctx: Context<SubmitLimitOrder>,
+        side: Side,
+        kind: StopOrderKind,
+        trigger_price: u64,
+        amount_in: u64,
+        limit_price: Option<u64>,
+        ref_id: u32,
+        market_maker_index: u8,
+        max_slippage_bps: u16,
+    ) -> Result<u8> {
+        let global_state = &ctx.accounts.global_state;
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        order_book.update_twap_oracle()?;
+
+        let fees = order_book.fees(global_state);
+
+        let market_maker = order_book.market_makers[market_maker_index as usize];
+
+        require!(
+            market_maker.can_trade(ctx.accounts.authority.key()),
+            CLOBError::UnauthorizedMarketMaker
+        );
+
+        let order = StopOrderParams {
+            side,
+            kind,
+            trigger_price,
+            amount_in,
+            limit_price,
+            ref_id,
+            max_slippage_bps,
+        };
+
+        Ok(order_book.submit_stop_order(order, market_maker_index, fees)?)
+    }
+
+    pub fn cancel_stop_order(
+        ctx: Context<CancelLimitOrder>,
+        stop_order_index: u8,
+        market_maker_index: u8,
+    ) -> Result<()> {
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        order_book.update_twap_oracle()?;
+
+        let market_maker = order_book.market_makers[market_maker_index as usize];
+
+        require!(
+            market_maker.can_trade(ctx.accounts.authority.key()),
+            CLOBError::UnauthorizedMarketMaker
+        );
+
+        let stop_order = order_book.stop_orders[stop_order_index as usize];
+
+        require!(
+            stop_order.market_maker_index == market_maker_index,
+            CLOBError::UnauthorizedMarketMaker
+        );
+
+        order_book.cancel_stop_order(stop_order_index as usize);
+
+        Ok(())
+    }
+
+    #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+    pub struct TakeOrderReceipt {
+        /// How much of `amount_in` was spent, including the taker fee
+        pub amount_in_filled: u64,
+        /// How much of `amount_in` couldn't be filled and was left with the user
+        pub amount_in_unfilled: u64,
+        pub amount_out: u64,
+        /// How much of the taker fee went to the referrer
+        pub referral_fee: u64,
+    }
+
+    #[event]
+    pub struct ReferralFeePaid {
+        pub order_book: Pubkey,
+        /// The referrer's token account
+        pub referrer: Pubkey,
+        pub mint: Pubkey,
+        pub amount: u64,
+    }
+
     pub fn submit_take_order(
         ctx: Context<SubmitTakeOrder>,
         side: Side,
         amount_in: u64,
         min_out: u64,
-    ) -> Result<()> {
+        limit_price: Option<u64>,
+        self_trade_behavior: SelfTradeBehavior,
+        fill_or_kill: bool,
+    ) -> Result<TakeOrderReceipt> {
         // TODO: add cluster restart logic, preventing take orders within x
         // slots of restart
 
@@ -371,135 +982,102 @@ pub mod clob {
 
         let global_state = &ctx.accounts.global_state;
 
-        let mut amount_in_after_fees = ((amount_in as u128)
-            * (MAX_BPS - global_state.taker_fee_in_bps) as u128)
-            / MAX_BPS as u128;
-
         let mut order_book = ctx.accounts.order_book.load_mut()?;
 
         order_book.update_twap_oracle()?;
 
-        let (receiving_vault, sending_vault, user_from, user_to) = match side {
-            Side::Buy => {
-                order_book.quote_fees_sweepable += amount_in - amount_in_after_fees as u64;
-                (
-                    &ctx.accounts.quote_vault,
-                    &ctx.accounts.base_vault,
-                    &ctx.accounts.user_quote_account,
-                    &ctx.accounts.user_base_account,
-                )
-            }
-            Side::Sell => {
-                order_book.base_fees_sweepable += amount_in - amount_in_after_fees as u64;
-                (
-                    &ctx.accounts.base_vault,
-                    &ctx.accounts.quote_vault,
-                    &ctx.accounts.user_base_account,
-                    &ctx.accounts.user_quote_account,
-                )
-            }
+        let mut fees = order_book.fees(global_state);
+        if ctx.accounts.referrer.is_some() {
+            fees.referral_share_bps = global_state.referral_share_bps;
+        }
+
+        let taker = Taker {
+            authority: ctx.accounts.authority.key(),
+            self_trade_behavior,
         };
 
-        token_transfer(
+        let (amount_in_unfilled, amount_out, referral_fee) = order_book.take(
+            side,
             amount_in,
-            &ctx.accounts.token_program,
-            &user_from,
-            &receiving_vault,
-            &ctx.accounts.authority,
+            taker,
+            fees,
+            limit_price,
+            Clock::get()?.slot,
         )?;
 
-        let mut amount_out = 0;
-        // We cannot delete the orders inside the loop because
-        // `order_list.iter()` holds an immutable borrow to the order list.
-        let mut filled_orders = Vec::new();
-
-        // If the user is buying, the maker is selling. If the maker is
-        // selling, the user is buying.
-        let (order_list, makers) = order_book.opposing_order_list(side);
-
-        for (book_order, book_order_idx) in order_list.iter() {
-            let order_amount_available = book_order.amount_in as u128; // u128s prevent overflow
-            let order_price = book_order.price as u128;
-
-            // If an order is selling 10 BONK at a price of 2 USDC per BONK,
-            // the order can take up to 5 USDC (10 / 2). If an order is buying
-            // BONK with 10 USDC at a price of 2 USDC per BONK, the order can
-            // take up to 20 BONK (10 * 2).
-            let amount_order_can_absorb = match side {
-                Side::Buy => (order_amount_available * PRICE_PRECISION) / order_price,
-                Side::Sell => (order_amount_available * order_price) / PRICE_PRECISION,
-            };
-
-            // Can the book order absorb all of a user's input token?
-            if amount_order_can_absorb >= amount_in_after_fees {
-                // If an order can absorb 15 USDC at a price of 3 USDC per BONK
-                // and a user is buying BONK with 6 USDC, the user should receive
-                // 2 BONK (6 / 3).
-                //
-                // If an order can absorb 20 BONK at a price of 3 USDC per BONK
-                // and a user is selling 10 BONK, the user should receive 30
-                // USDC (10 * 3).
-                let user_to_receive = match side {
-                    Side::Buy => (amount_in_after_fees * PRICE_PRECISION) / order_price,
-                    Side::Sell => (amount_in_after_fees * order_price) / PRICE_PRECISION,
-                } as u64;
-                amount_out += user_to_receive;
-
-                order_list.orders[book_order_idx as usize].amount_in -= user_to_receive;
-
-                match side {
-                    Side::Buy => {
-                        makers[book_order.market_maker_index as usize].quote_balance +=
-                            amount_in_after_fees as u64
-                    }
-                    Side::Sell => {
-                        makers[book_order.market_maker_index as usize].base_balance +=
-                            amount_in_after_fees as u64
-                    }
-                };
+        require!(amount_out >= min_out, CLOBError::TakeNotFilled);
 
-                break;
-            } else {
-                amount_in_after_fees -= amount_order_can_absorb;
-                amount_out += order_amount_available as u64;
-
-                match side {
-                    Side::Buy => {
-                        makers[book_order.market_maker_index as usize].quote_balance +=
-                            amount_order_can_absorb as u64
-                    }
-                    Side::Sell => {
-                        makers[book_order.market_maker_index as usize].base_balance +=
-                            amount_order_can_absorb as u64
-                    }
-                };
-
-                filled_orders.push(book_order_idx);
-            }
+        // `min_out` can't express this, since the book may run out before
+        // `amount_in` does
+        if fill_or_kill {
+            require!(amount_in_unfilled == 0, CLOBError::FillOrKillNotFilled);
         }
 
-        for order_idx in filled_orders {
-            order_list.delete_order(order_idx, makers);
+        drop(order_book);
+
+        // Rather than taking all of `amount_in` and sending back what couldn't
+        // be filled, only take what was filled.
+        let amount_in_filled = amount_in - amount_in_unfilled;
+
+        settle_take(
+            ctx.accounts,
+            side,
+            amount_in_filled,
+            amount_out,
+            referral_fee,
+        )?;
+
+        Ok(TakeOrderReceipt {
+            amount_in_filled,
+            amount_in_unfilled,
+            amount_out,
+            referral_fee,
+        })
+    }
+
+    /// Buys or sells exactly `amount_out` of the other token, paying no more
+    /// than `max_in` for it, including the taker fee.
+    pub fn submit_take_order_exact_out(
+        ctx: Context<SubmitTakeOrder>,
+        side: Side,
+        amount_out: u64,
+        max_in: u64,
+        self_trade_behavior: SelfTradeBehavior,
+    ) -> Result<TakeOrderReceipt> {
+        assert!(amount_out > 0);
+
+        let global_state = &ctx.accounts.global_state;
+
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        order_book.update_twap_oracle()?;
+
+        let mut fees = order_book.fees(global_state);
+        if ctx.accounts.referrer.is_some() {
+            fees.referral_share_bps = global_state.referral_share_bps;
         }
 
-        require!(amount_out >= min_out, CLOBError::TakeNotFilled);
+        let taker = Taker {
+            authority: ctx.accounts.authority.key(),
+            self_trade_behavior,
+        };
 
-        let base = order_book.base;
-        let quote = order_book.quote;
-        let pda_bump = order_book.pda_bump;
+        let (amount_in, amount_out_filled, referral_fee) =
+            order_book.take_exact_out(side, amount_out, taker, fees, Clock::get()?.slot)?;
 
-        let seeds = &[b"order_book", base.as_ref(), quote.as_ref(), &[pda_bump]];
+        require!(amount_out_filled == amount_out, CLOBError::TakeNotFilled);
+        require!(amount_in <= max_in, CLOBError::MaxInExceeded);
 
         drop(order_book);
 
-        token_transfer_signed(
+        settle_take(ctx.accounts, side, amount_in, amount_out, referral_fee)?;
+
+        Ok(TakeOrderReceipt {
+            amount_in_filled: amount_in,
+            amount_in_unfilled: 0,
             amount_out,
-            &ctx.accounts.token_program,
-            sending_vault,
-            user_to,
-            &ctx.accounts.order_book,
-            seeds,
-        )
+            referral_fee,
+        })
     }
 
     /**** GETTERS ****/
@@ -547,7 +1125,7 @@ pub mod clob {
             Side::Sell => order_book.sells,
         };
 
-        for (order, order_idx) in order_list.iter() {
+        for (order, order_idx) in order_list.iter_unexpired(Clock::get()?.slot) {
             if order.ref_id == ref_id && order.market_maker_index == market_maker_index {
                 return Ok(Some(order_idx));
             }
@@ -574,7 +1152,7 @@ pub mod clob {
 
         let mut orders = Vec::with_capacity(max_returnable);
 
-        for (order, _) in order_list.iter() {
+        for (order, _) in order_list.iter_unexpired(Clock::get()?.slot) {
             orders.push(AmountAndPrice {
                 amount: order.amount_in,
                 price: order.price,
@@ -588,3 +1166,82 @@ pub mod clob {
         Ok(orders)
     }
 }
+
+/// Moves a filled take's tokens: `amount_in` from the user, of which
+/// `referral_fee` goes to the referrer, and `amount_out` to the user.
+/// The order book must not be borrowed when this is called.
+///
+/// This lives outside of `clob`, where every function is an instruction.
+fn settle_take(
+    accounts: &SubmitTakeOrder,
+    side: Side,
+    amount_in: u64,
+    amount_out: u64,
+    referral_fee: u64,
+) -> Result<()> {
+    let (receiving_vault, sending_vault, user_from, user_to) = match side {
+        Side::Buy => (
+            &accounts.quote_vault,
+            &accounts.base_vault,
+            &accounts.user_quote_account,
+            &accounts.user_base_account,
+        ),
+        Side::Sell => (
+            &accounts.base_vault,
+            &accounts.quote_vault,
+            &accounts.user_base_account,
+            &accounts.user_quote_account,
+        ),
+    };
+
+    let order_book = accounts.order_book.load()?;
+
+    let base = order_book.base;
+    let quote = order_book.quote;
+    let pda_bump = order_book.pda_bump;
+
+    let seeds = &[b"order_book", base.as_ref(), quote.as_ref(), &[pda_bump]];
+
+    drop(order_book);
+
+    token_transfer(
+        amount_in - referral_fee,
+        &accounts.token_program,
+        user_from,
+        receiving_vault,
+        &accounts.authority,
+    )?;
+
+    if let Some(referrer) = &accounts.referrer {
+        // The referral fee is paid in the same token as the rest of the fee
+        require_keys_eq!(
+            referrer.mint,
+            receiving_vault.mint,
+            CLOBError::ReferrerMintMismatch
+        );
+
+        token_transfer(
+            referral_fee,
+            &accounts.token_program,
+            user_from,
+            referrer,
+            &accounts.authority,
+        )?;
+
+        emit!(clob::ReferralFeePaid {
+            order_book: accounts.order_book.key(),
+            referrer: referrer.key(),
+            mint: referrer.mint,
+            amount: referral_fee,
+        });
+    }
+
+    token_transfer_signed(
+        amount_out,
+        &accounts.token_program,
+        sending_vault,
+        user_to,
+        &accounts.order_book,
+        seeds,
+    )
+}
@@ -0,0 +1,12 @@
+use super::*;
+
+/// What happens to the lamports paid to add a market maker.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum MarketMakerDepositMode {
+    /// Send them to the admin
+    PayAdmin,
+    /// Hold them in the order book and refund them when the seat is removed
+    Escrow,
+    /// Send them to the incinerator, which burns them
+    Burn,
+}
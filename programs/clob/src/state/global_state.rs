@@ -11,8 +11,31 @@ pub struct GlobalState {
     /// The CLOB needs fees to disincentivize wash trading / TWAP manipulation.
     /// Besides, profits are virtuous :)
     pub taker_fee_in_bps: u16,
+    /// How much of the taker fee goes to the makers whose orders are filled,
+    /// in basis points of what they are paid, to reward tight quotes. Can't
+    /// exceed `taker_fee_in_bps`.
+    pub maker_rebate_bps: u16,
+    /// How much of the taker fee left after maker rebates goes to the
+    /// referrer of a take, in basis points, for front-ends routing flow here.
+    pub referral_share_bps: u16,
     /// Since market maker slots are finite, we need some cost to prevent someone
     /// from taking all the market maker slots. Also, have I mentioned that profits
     /// are virtuous?
     pub market_maker_burn_in_lamports: u64,
+    /// Where `market_maker_burn_in_lamports` goes.
+    pub market_maker_deposit_mode: MarketMakerDepositMode,
+    /// How many slots must pass after a market maker is added to an order book
+    /// before another can be added to it, so that no one can grab every seat at
+    /// once.
+    pub market_maker_cooldown_slots: u64,
+}
+
+/// What a take pays in fees and, out of those, what its makers are paid back
+/// and what its referrer gets.
+#[derive(Clone, Copy)]
+pub struct Fees {
+    pub taker_fee_in_bps: u16,
+    pub maker_rebate_bps: u16,
+    /// 0 unless the take has a referrer
+    pub referral_share_bps: u16,
 }
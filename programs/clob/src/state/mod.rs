@@ -1,11 +1,19 @@
 use super::*;
 
+pub mod deposit_mode;
 pub mod free_bitmap;
 pub mod global_state;
 pub mod order_book;
+pub mod order_type;
+pub mod self_trade;
 pub mod side;
+pub mod stop_order;
 
+pub use deposit_mode::*;
 pub use free_bitmap::*;
 pub use global_state::*;
 pub use order_book::*;
+pub use order_type::*;
+pub use self_trade::*;
 pub use side::*;
+pub use stop_order::*;
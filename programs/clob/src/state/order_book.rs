@@ -15,18 +15,61 @@ pub struct OrderBook {
     pub buys: OrderList,
     pub sells: OrderList,
     pub market_makers: [MarketMaker; NUM_MARKET_MAKERS],
+    pub stop_orders: [StopOrder; NUM_STOP_ORDERS],
     pub twap_oracle: TWAPOracle,
     // The minimum amount of base/quote tokens that a limit order can offer,
     // to prevent spam.
     pub min_base_limit_amount: u64,
     pub min_quote_limit_amount: u64,
+    // Limit orders must be priced at a multiple of `tick_size`, and offer a
+    // multiple of `base_lot_size`/`quote_lot_size` of whichever token they
+    // offer, so that prices and sizes don't fragment the book.
+    pub tick_size: u64,
+    pub base_lot_size: u64,
+    pub quote_lot_size: u64,
     pub base_fees_sweepable: u64,
     pub quote_fees_sweepable: u64,
+    // When the last market maker was added, or 0 if none have been
+    pub last_market_maker_added_slot: u64,
+    // How far, in basis points, a limit order's price can be from the TWAP
+    // oracle's last observation, or 0 for no limit. This keeps far-crossing
+    // orders from dragging the observation.
+    pub price_band_bps: u16,
+    // If `has_taker_fee_override` is set, takes on this order book pay
+    // `taker_fee_override_in_bps` rather than the global taker fee.
+    pub taker_fee_override_in_bps: u16,
+    pub has_taker_fee_override: u8,
     pub pda_bump: u8,
-    pub _padding: [u8; 7],
+    pub _padding: [u8; 2],
 }
 
 impl OrderBook {
+    /// The fees that takes on this order book pay, which are the global ones
+    /// unless this order book overrides the taker fee. The maker rebate is
+    /// capped at the taker fee, since it is paid out of it.
+    pub fn fees(&self, global_state: &GlobalState) -> Fees {
+        let taker_fee_in_bps = if self.has_taker_fee_override != 0 {
+            self.taker_fee_override_in_bps
+        } else {
+            global_state.taker_fee_in_bps
+        };
+
+        Fees {
+            taker_fee_in_bps,
+            maker_rebate_bps: std::cmp::min(global_state.maker_rebate_bps, taker_fee_in_bps),
+            referral_share_bps: 0,
+        }
+    }
+
+    /// The minimum amount and the lot size of a `side` limit order, in the
+    /// token that it offers.
+    pub fn min_amount_and_lot_size(&self, side: Side) -> (u64, u64) {
+        match side {
+            Side::Buy => (self.min_quote_limit_amount, self.quote_lot_size),
+            Side::Sell => (self.min_base_limit_amount, self.base_lot_size),
+        }
+    }
+
     pub fn opposing_order_list(
         &mut self,
         side: Side,
@@ -49,12 +92,686 @@ impl OrderBook {
         (list, &mut self.market_makers)
     }
 
+    /// Submits a limit order on behalf of the maker at `market_maker_index`,
+    /// returning the index it rests at, or `None` if it crossed the book and
+    /// nothing is left of it to rest.
+    ///
+    /// If the order crosses the book, it first fills against the opposing
+    /// orders at their prices, paying the taker fee like a take order would.
+    /// Only what remains afterwards rests on the book, rounded down to the
+    /// lot size, and only if that meets the minimum amount.
+    ///
+    /// Any of the maker's authority's own orders that it crosses are cancelled
+    /// rather than filled.
+    ///
+    /// Every check happens before the book is touched, so a rejected order
+    /// leaves it as it was.
+    pub fn submit_limit_order(
+        &mut self,
+        order: LimitOrderParams,
+        market_maker_index: u8,
+        fees: Fees,
+        slot: u64,
+    ) -> std::result::Result<Option<u8>, CLOBError> {
+        let LimitOrderParams {
+            side,
+            amount_in,
+            price,
+            ref_id,
+            expiry_slot,
+            peg_offset_bps,
+            order_type,
+            hidden_amount,
+        } = order;
+        let hidden_amount = hidden_amount.unwrap_or(0);
+
+        // An `expiry_slot` of 0 is how orders that never expire are stored
+        let expiry_slot = match expiry_slot {
+            Some(expiry_slot) if expiry_slot < slot => return Err(CLOBError::OrderAlreadyExpired),
+            Some(expiry_slot) => expiry_slot,
+            None => 0,
+        };
+
+        // Pegged orders ignore `price` and instead float relative to the TWAP
+        let price = match peg_offset_bps {
+            Some(peg_offset_bps) => {
+                if peg_offset_bps <= -(MAX_BPS as i16) {
+                    return Err(CLOBError::PegOffsetOutOfRange);
+                }
+                if self.twap_oracle.last_updated_slot == 0 {
+                    return Err(CLOBError::NoObservationToPegTo);
+                }
+                self.twap_oracle
+                    .pegged_price(peg_offset_bps, side, self.tick_size)
+            }
+            None => price,
+        };
+
+        let price = self.post_only_price(side, price, order_type)?;
+
+        if price == 0 {
+            return Err(CLOBError::ZeroPrice);
+        }
+
+        let (min_amount, lot_size) = self.min_amount_and_lot_size(side);
+        if amount_in < min_amount {
+            return Err(CLOBError::MinLimitAmountNotMet);
+        }
+
+        if price % self.tick_size != 0 {
+            return Err(CLOBError::PriceNotOnTick);
+        }
+        if !self.is_within_price_band(price) {
+            return Err(CLOBError::PriceOutsideBand);
+        }
+        if amount_in % lot_size != 0 || hidden_amount % lot_size != 0 {
+            return Err(CLOBError::AmountNotOnLot);
+        }
+
+        // Crossing the book never changes this order's own side, so we can
+        // tell upfront whether a remainder would have room to rest.
+        let (order_list, _) = self.order_list(side);
+        if !order_list.has_room_for(price) {
+            return Err(CLOBError::InferiorPrice);
+        }
+
+        // An iceberg order fills its hidden amount too when it crosses the book
+        let total_amount_in = amount_in
+            .checked_add(hidden_amount)
+            .ok_or(CLOBError::InsufficientBalance)?;
+
+        let market_maker = &mut self.market_makers[market_maker_index as usize];
+        let taker = Taker {
+            authority: market_maker.authority,
+            self_trade_behavior: SelfTradeBehavior::CancelResting,
+        };
+        match side {
+            Side::Buy => {
+                market_maker.quote_balance = market_maker
+                    .quote_balance
+                    .checked_sub(total_amount_in)
+                    .ok_or(CLOBError::InsufficientBalance)?;
+            }
+            Side::Sell => {
+                market_maker.base_balance = market_maker
+                    .base_balance
+                    .checked_sub(total_amount_in)
+                    .ok_or(CLOBError::InsufficientBalance)?;
+            }
+        }
+
+        let (amount_unfilled, amount_out, _) =
+            self.take(side, total_amount_in, taker, fees, Some(price), slot)?;
+
+        // `insert_order` debits whatever is left over again
+        let market_maker = &mut self.market_makers[market_maker_index as usize];
+        match side {
+            Side::Buy => {
+                market_maker.quote_balance += amount_unfilled;
+                market_maker.base_balance += amount_out;
+            }
+            Side::Sell => {
+                market_maker.base_balance += amount_unfilled;
+                market_maker.quote_balance += amount_out;
+            }
+        }
+
+        // Filling can leave a remainder that is off the lot grid or too small
+        // to rest, which stays with the maker instead
+        let amount_to_rest = amount_unfilled - amount_unfilled % lot_size;
+        if amount_to_rest < min_amount {
+            return Ok(None);
+        }
+
+        // What's left is shown a slice at a time, with the rest hidden
+        let slice_amount = amount_in;
+        let amount_in = std::cmp::min(amount_to_rest, slice_amount);
+        let hidden_amount = amount_to_rest - amount_in;
+
+        let (order_list, makers) = self.order_list(side);
+
+        let order = Order {
+            amount_in,
+            price,
+            ref_id,
+            market_maker_index,
+            expiry_slot,
+            hidden_amount,
+            slice_amount,
+            is_pegged: peg_offset_bps.is_some().into(),
+            peg_offset_bps: peg_offset_bps.unwrap_or(0),
+            ..Default::default()
+        };
+
+        let order_idx = order_list.insert_order(order, makers);
+
+        // We already checked that there is room for this order
+        assert!(order_idx.is_some());
+
+        Ok(order_idx)
+    }
+
+    /// Applies a limit order's `order_type` to its `price`, rejecting a
+    /// post-only order that would cross the book and sliding a post-only-slide
+    /// order to one tick behind the best opposing order.
+    fn post_only_price(
+        &self,
+        side: Side,
+        price: u64,
+        order_type: OrderType,
+    ) -> std::result::Result<u64, CLOBError> {
+        let opposing_order_list = match side {
+            Side::Buy => &self.sells,
+            Side::Sell => &self.buys,
+        };
+
+        if order_type == OrderType::Limit || !opposing_order_list.is_crossed_by(price) {
+            return Ok(price);
+        }
+
+        if order_type == OrderType::PostOnly {
+            return Err(CLOBError::PostOnlyWouldCross);
+        }
+
+        // `is_crossed_by` means that there is a best order
+        let (best_order, _) = opposing_order_list.iter().next().unwrap();
+
+        match price_behind(side, best_order.price, self.tick_size) {
+            0 => Err(CLOBError::PostOnlyWouldCross),
+            price => Ok(price),
+        }
+    }
+
+    /// Escrows a stop order's `amount_in` from the maker at
+    /// `market_maker_index`, returning the index the stop order is stored at.
+    ///
+    /// Once triggered, it becomes a limit order at `limit_price` if there is
+    /// one and a take against the opposing orders otherwise, paying the
+    /// `fees` in effect now for whatever it fills. The take only fills at
+    /// prices within `max_slippage_bps` of the observation that triggered it,
+    /// leaving the rest with the maker.
+    pub fn submit_stop_order(
+        &mut self,
+        order: StopOrderParams,
+        market_maker_index: u8,
+        fees: Fees,
+    ) -> std::result::Result<u8, CLOBError> {
+        let StopOrderParams {
+            side,
+            kind,
+            trigger_price,
+            amount_in,
+            limit_price,
+            ref_id,
+            max_slippage_bps,
+        } = order;
+
+        let (min_amount, lot_size) = self.min_amount_and_lot_size(side);
+        if amount_in < min_amount {
+            return Err(CLOBError::MinLimitAmountNotMet);
+        }
+        if amount_in % lot_size != 0 {
+            return Err(CLOBError::AmountNotOnLot);
+        }
+        if let Some(limit_price) = limit_price {
+            if limit_price == 0 || limit_price % self.tick_size != 0 {
+                return Err(CLOBError::PriceNotOnTick);
+            }
+        }
+        if max_slippage_bps >= MAX_BPS {
+            return Err(CLOBError::MaxSlippageOutOfRange);
+        }
+
+        let num_maker_stop_orders = self
+            .stop_orders
+            .iter()
+            .filter(|stop_order| {
+                stop_order.amount_in > 0 && stop_order.market_maker_index == market_maker_index
+            })
+            .count();
+        if num_maker_stop_orders >= MAX_STOP_ORDERS_PER_MARKET_MAKER {
+            return Err(CLOBError::TooManyStopOrders);
+        }
+
+        let stop_order_idx = self
+            .stop_orders
+            .iter()
+            .position(|stop_order| stop_order.amount_in == 0)
+            .ok_or(CLOBError::StopOrderListFull)?;
+
+        let market_maker = &mut self.market_makers[market_maker_index as usize];
+        match side {
+            Side::Buy => {
+                market_maker.quote_balance = market_maker
+                    .quote_balance
+                    .checked_sub(amount_in)
+                    .ok_or(CLOBError::InsufficientBalance)?;
+            }
+            Side::Sell => {
+                market_maker.base_balance = market_maker
+                    .base_balance
+                    .checked_sub(amount_in)
+                    .ok_or(CLOBError::InsufficientBalance)?;
+            }
+        }
+
+        let triggers_above = match (side, kind) {
+            (Side::Buy, StopOrderKind::StopLoss) | (Side::Sell, StopOrderKind::TakeProfit) => 1,
+            (Side::Buy, StopOrderKind::TakeProfit) | (Side::Sell, StopOrderKind::StopLoss) => 0,
+        };
+
+        self.stop_orders[stop_order_idx] = StopOrder {
+            trigger_price,
+            amount_in,
+            limit_price: limit_price.unwrap_or(0),
+            ref_id,
+            taker_fee_in_bps: fees.taker_fee_in_bps,
+            maker_rebate_bps: fees.maker_rebate_bps,
+            max_slippage_bps,
+            market_maker_index,
+            side: side.into(),
+            triggers_above,
+            ..Default::default()
+        };
+
+        Ok(stop_order_idx as u8)
+    }
+
+    /// Removes the stop order at `i`, returning its escrowed tokens to its
+    /// maker.
+    pub fn cancel_stop_order(&mut self, i: usize) {
+        let stop_order = self.stop_orders[i];
+        let market_maker = &mut self.market_makers[stop_order.market_maker_index as usize];
+
+        match stop_order.side.into() {
+            Side::Buy => market_maker.quote_balance += stop_order.amount_in,
+            Side::Sell => market_maker.base_balance += stop_order.amount_in,
+        }
+
+        self.stop_orders[i] = StopOrder::default();
+    }
+
+    /// Cancels every order and stop order placed by the maker at
+    /// `market_maker_index` and frees up its seat, returning the base and
+    /// quote balances it had left to be paid out. Its deposit, if any, is
+    /// left for the caller to refund.
+    pub fn remove_market_maker(&mut self, market_maker_index: u8) -> (u64, u64) {
+        self.buys
+            .delete_maker_orders(market_maker_index, &mut self.market_makers);
+        self.sells
+            .delete_maker_orders(market_maker_index, &mut self.market_makers);
+
+        for i in 0..NUM_STOP_ORDERS {
+            let stop_order = self.stop_orders[i];
+            if stop_order.amount_in > 0 && stop_order.market_maker_index == market_maker_index {
+                self.cancel_stop_order(i);
+            }
+        }
+
+        let market_maker = &mut self.market_makers[market_maker_index as usize];
+        let balances = (market_maker.base_balance, market_maker.quote_balance);
+
+        market_maker.base_balance = 0;
+        market_maker.quote_balance = 0;
+        market_maker.deposit_lamports = 0;
+        market_maker.authority = Pubkey::default();
+        market_maker.pending_authority = Pubkey::default();
+        market_maker.delegate = Pubkey::default();
+
+        balances
+    }
+
+    /// Activates every stop order whose trigger price the TWAP oracle's last
+    /// observation has reached.
+    fn trigger_stop_orders(&mut self, slot: u64) -> std::result::Result<(), CLOBError> {
+        let observation = self.twap_oracle.last_observation;
+
+        for i in 0..NUM_STOP_ORDERS {
+            let stop_order = self.stop_orders[i];
+
+            if stop_order.amount_in == 0 || !stop_order.is_triggered_by(observation) {
+                continue;
+            }
+
+            // From here on the maker trades the escrowed tokens like any
+            // other limit order or take would.
+            self.cancel_stop_order(i);
+
+            let side = stop_order.side.into();
+            let market_maker_index = stop_order.market_maker_index;
+
+            if stop_order.limit_price != 0 {
+                let order = LimitOrderParams {
+                    side,
+                    amount_in: stop_order.amount_in,
+                    price: stop_order.limit_price,
+                    ref_id: stop_order.ref_id,
+                    expiry_slot: None,
+                    peg_offset_bps: None,
+                    order_type: OrderType::Limit,
+                    hidden_amount: None,
+                };
+
+                // A limit order that would be rejected now is dropped,
+                // leaving its tokens with its maker.
+                self.submit_limit_order(order, market_maker_index, stop_order.fees(), slot)
+                    .ok();
+
+                continue;
+            }
+
+            let market_maker = &mut self.market_makers[market_maker_index as usize];
+            let taker = Taker {
+                authority: market_maker.authority,
+                self_trade_behavior: SelfTradeBehavior::CancelResting,
+            };
+            match side {
+                Side::Buy => market_maker.quote_balance -= stop_order.amount_in,
+                Side::Sell => market_maker.base_balance -= stop_order.amount_in,
+            }
+
+            let (amount_unfilled, amount_out, _) = self.take(
+                side,
+                stop_order.amount_in,
+                taker,
+                stop_order.fees(),
+                Some(stop_order.take_limit_price(observation)),
+                slot,
+            )?;
+
+            let market_maker = &mut self.market_makers[market_maker_index as usize];
+            match side {
+                Side::Buy => {
+                    market_maker.quote_balance += amount_unfilled;
+                    market_maker.base_balance += amount_out;
+                }
+                Side::Sell => {
+                    market_maker.base_balance += amount_unfilled;
+                    market_maker.quote_balance += amount_out;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Is `price` within `price_band_bps` of the TWAP oracle's last observation?
+    /// Always true if there is no band or no observation yet.
+    pub fn is_within_price_band(&self, price: u64) -> bool {
+        if self.price_band_bps == 0 || self.twap_oracle.last_updated_slot == 0 {
+            return true;
+        }
+
+        let observation = self.twap_oracle.last_observation as u128;
+        let min_price = (observation * (MAX_BPS - self.price_band_bps) as u128) / MAX_BPS as u128;
+        let max_price = (observation * (MAX_BPS + self.price_band_bps) as u128) / MAX_BPS as u128;
+
+        (min_price..=max_price).contains(&(price as u128))
+    }
+
+    /// Fills `amount_in` of a taker's tokens against the orders opposing
+    /// `side`, charging the taker fee on the part that gets filled and paying
+    /// the maker rebate out of it. If `limit_price` is given, orders priced
+    /// worse than it are not filled.
+    ///
+    /// Returns how much of `amount_in` went unfilled, including the fee that
+    /// was not charged on it, how much the taker should receive, and how much
+    /// of the fee goes to their referrer. It is the client's responsibility to
+    /// move the taker's tokens.
+    pub fn take(
+        &mut self,
+        side: Side,
+        amount_in: u64,
+        taker: Taker,
+        fees: Fees,
+        limit_price: Option<u64>,
+        slot: u64,
+    ) -> std::result::Result<(u64, u64, u64), CLOBError> {
+        let amount_in_after_fees = ((amount_in as u128 * (MAX_BPS - fees.taker_fee_in_bps) as u128)
+            / MAX_BPS as u128) as u64;
+
+        let (amount_unfilled_after_fees, amount_out, maker_rebates) = self.fill_against_book(
+            side,
+            amount_in_after_fees,
+            taker,
+            fees.maker_rebate_bps,
+            limit_price,
+            slot,
+        )?;
+
+        // Round the refunded fee down so that we never refund more than we charged
+        let fee = amount_in - amount_in_after_fees;
+        let fee_refund = if amount_in_after_fees > 0 {
+            ((fee as u128 * amount_unfilled_after_fees as u128) / amount_in_after_fees as u128)
+                as u64
+        } else {
+            fee
+        };
+
+        // The rebate's rate is at most the fee's, and it only applies to what
+        // is left after the fee, so the fee charged always covers it
+        let referral_fee = self.collect_fee(
+            side,
+            fee - fee_refund - maker_rebates,
+            fees.referral_share_bps,
+        );
+
+        Ok((
+            amount_unfilled_after_fees + fee_refund,
+            amount_out,
+            referral_fee,
+        ))
+    }
+
+    /// Adds what's left of a take's fee after maker rebates to the sweepable
+    /// fees, less the referrer's `referral_share_bps` of it, which is returned.
+    fn collect_fee(&mut self, side: Side, fee: u64, referral_share_bps: u16) -> u64 {
+        let referral_fee = ((fee as u128 * referral_share_bps as u128) / MAX_BPS as u128) as u64;
+
+        match side {
+            Side::Buy => self.quote_fees_sweepable += fee - referral_fee,
+            Side::Sell => self.base_fees_sweepable += fee - referral_fee,
+        };
+
+        referral_fee
+    }
+
+    /// Fills `amount_in` of a taker's tokens against the orders opposing
+    /// `side`, best price first, crediting the makers of the filled orders
+    /// along with a rebate of `maker_rebate_bps` of what they are paid.
+    /// Returns how much of `amount_in` went unfilled, how much the taker
+    /// should receive, and how much was paid out in rebates.
+    pub fn fill_against_book(
+        &mut self,
+        side: Side,
+        amount_in: u64,
+        taker: Taker,
+        maker_rebate_bps: u16,
+        limit_price: Option<u64>,
+        slot: u64,
+    ) -> std::result::Result<(u64, u64, u64), CLOBError> {
+        let mut amount_in_remaining = amount_in as u128; // u128s prevent overflow
+        let mut amount_out = 0;
+        let mut maker_rebates = 0;
+
+        // If the user is buying, the maker is selling. If the maker is
+        // selling, the user is buying.
+        let (order_list, makers) = self.opposing_order_list(side);
+
+        let mut i = order_list.best_order_idx;
+        while i != NULL && amount_in_remaining > 0 {
+            let book_order = order_list.orders[i as usize];
+            let next_idx = book_order.next_idx;
+
+            if let Some(limit_price) = limit_price {
+                if order_list.is_price_better(limit_price, book_order.price) {
+                    break;
+                }
+            }
+
+            if !order_list.can_fill_against(i, taker, slot, makers)? {
+                i = next_idx;
+                continue;
+            }
+
+            let order_amount_available = book_order.amount_in as u128;
+            let order_price = book_order.price as u128;
+
+            // If an order is selling 10 BONK at a price of 2 USDC per BONK,
+            // the order can take up to 20 USDC (10 * 2). If an order is buying
+            // BONK with 10 USDC at a price of 2 USDC per BONK, the order can
+            // take up to 5 BONK (10 / 2).
+            let amount_order_can_absorb = match side {
+                Side::Buy => (order_amount_available * order_price) / PRICE_PRECISION,
+                Side::Sell => (order_amount_available * PRICE_PRECISION) / order_price,
+            };
+
+            // Can the book order absorb all of a user's input token?
+            if amount_order_can_absorb >= amount_in_remaining {
+                // If an order can absorb 15 USDC at a price of 3 USDC per BONK
+                // and a user is buying BONK with 6 USDC, the user should receive
+                // 2 BONK (6 / 3).
+                //
+                // If an order can absorb 20 BONK at a price of 3 USDC per BONK
+                // and a user is selling 10 BONK, the user should receive 30
+                // USDC (10 * 3).
+                let user_to_receive = match side {
+                    Side::Buy => (amount_in_remaining * PRICE_PRECISION) / order_price,
+                    Side::Sell => (amount_in_remaining * order_price) / PRICE_PRECISION,
+                } as u64;
+                amount_out += user_to_receive;
+
+                let (next_idx, maker_rebate) = order_list.fill_order(
+                    i,
+                    user_to_receive,
+                    amount_in_remaining as u64,
+                    maker_rebate_bps,
+                    makers,
+                );
+                maker_rebates += maker_rebate;
+                i = next_idx;
+
+                amount_in_remaining = 0;
+            } else {
+                amount_in_remaining -= amount_order_can_absorb;
+                amount_out += order_amount_available as u64;
+
+                let (next_idx, maker_rebate) = order_list.fill_order(
+                    i,
+                    order_amount_available as u64,
+                    amount_order_can_absorb as u64,
+                    maker_rebate_bps,
+                    makers,
+                );
+                maker_rebates += maker_rebate;
+                i = next_idx;
+            }
+        }
+
+        Ok((amount_in_remaining as u64, amount_out, maker_rebates))
+    }
+
+    /// Like `take`, but fills until the taker has received `amount_out`.
+    ///
+    /// Returns how much the taker needs to pay for what was filled, including
+    /// the taker fee, how much they should receive, which is less than
+    /// `amount_out` if the book ran out, and how much of the fee goes to their
+    /// referrer. It is the client's responsibility to move the taker's tokens.
+    pub fn take_exact_out(
+        &mut self,
+        side: Side,
+        amount_out: u64,
+        taker: Taker,
+        fees: Fees,
+        slot: u64,
+    ) -> std::result::Result<(u64, u64, u64), CLOBError> {
+        let (amount_in_after_fees, amount_out, maker_rebates) =
+            self.fill_exact_out_against_book(side, amount_out, taker, fees.maker_rebate_bps, slot)?;
+
+        // Round the fee up so that `take` would charge no less for this
+        let amount_in = u64::try_from(div_ceil(
+            amount_in_after_fees as u128 * MAX_BPS as u128,
+            (MAX_BPS - fees.taker_fee_in_bps) as u128,
+        ))
+        .map_err(|_| CLOBError::MaxInExceeded)?;
+        let fee = amount_in - amount_in_after_fees;
+
+        let referral_fee = self.collect_fee(side, fee - maker_rebates, fees.referral_share_bps);
+
+        Ok((amount_in, amount_out, referral_fee))
+    }
+
+    /// Fills orders opposing `side`, best price first, until the taker has
+    /// received `amount_out`, crediting the makers of the filled orders along
+    /// with a rebate of `maker_rebate_bps` of what they are paid. Returns how
+    /// much the makers need to be paid, how much the taker should receive,
+    /// and how much was paid out in rebates.
+    pub fn fill_exact_out_against_book(
+        &mut self,
+        side: Side,
+        amount_out: u64,
+        taker: Taker,
+        maker_rebate_bps: u16,
+        slot: u64,
+    ) -> std::result::Result<(u64, u64, u64), CLOBError> {
+        let mut amount_out_remaining = amount_out;
+        let mut amount_in: u64 = 0;
+        let mut maker_rebates = 0;
+
+        let (order_list, makers) = self.opposing_order_list(side);
+
+        let mut i = order_list.best_order_idx;
+        while i != NULL && amount_out_remaining > 0 {
+            let book_order = order_list.orders[i as usize];
+            let next_idx = book_order.next_idx;
+
+            if !order_list.can_fill_against(i, taker, slot, makers)? {
+                i = next_idx;
+                continue;
+            }
+
+            let amount_filled = std::cmp::min(book_order.amount_in, amount_out_remaining);
+            let order_price = book_order.price as u128; // u128s prevent overflow
+
+            // If a user wants 10 BONK from an order selling it at 2 USDC per
+            // BONK, they need to pay 20 USDC (10 * 2). If a user wants 10 USDC
+            // from an order buying BONK at 2 USDC per BONK, they need to pay 5
+            // BONK (10 / 2). Round up so that makers are never underpaid.
+            // The taker picks `amount_out`, so the amount to pay may not fit
+            // in a `u64`, and in that case it's more than any `max_in`.
+            let amount_to_pay = u64::try_from(match side {
+                Side::Buy => div_ceil(amount_filled as u128 * order_price, PRICE_PRECISION),
+                Side::Sell => div_ceil(amount_filled as u128 * PRICE_PRECISION, order_price),
+            })
+            .map_err(|_| CLOBError::MaxInExceeded)?;
+
+            amount_in = amount_in
+                .checked_add(amount_to_pay)
+                .ok_or(CLOBError::MaxInExceeded)?;
+            amount_out_remaining -= amount_filled;
+
+            let (next_idx, maker_rebate) =
+                order_list.fill_order(i, amount_filled, amount_to_pay, maker_rebate_bps, makers);
+            maker_rebates += maker_rebate;
+            i = next_idx;
+        }
+
+        Ok((amount_in, amount_out - amount_out_remaining, maker_rebates))
+    }
+
     pub fn update_twap_oracle(&mut self) -> Result<()> {
         let clock = Clock::get()?;
 
         let oracle = &mut self.twap_oracle;
 
         if clock.slot > oracle.last_updated_slot {
+            // Orders can only expire when the slot advances, so sweeping them
+            // on the first update of each slot keeps the book free of them.
+            self.buys
+                .delete_expired_orders(clock.slot, &mut self.market_makers);
+            self.sells
+                .delete_expired_orders(clock.slot, &mut self.market_makers);
+
             let best_bid = self.buys.iter().next();
             let best_offer = self.sells.iter().next();
 
@@ -117,6 +834,25 @@ impl OrderBook {
             oracle.last_updated_slot = clock.slot;
             oracle.last_observation = observation;
             oracle.observation_aggregator += weighted_observation as u128;
+
+            // Each side is kept behind the other's best order, so repricing
+            // can't cross the book
+            let best_offer = self.sells.iter().next().map(|(order, _)| order.price);
+            self.buys.reprice_pegged_orders(
+                &self.twap_oracle,
+                self.tick_size,
+                best_offer,
+                &mut self.market_makers,
+            );
+            let best_bid = self.buys.iter().next().map(|(order, _)| order.price);
+            self.sells.reprice_pegged_orders(
+                &self.twap_oracle,
+                self.tick_size,
+                best_bid,
+                &mut self.market_makers,
+            );
+
+            self.trigger_stop_orders(clock.slot)?;
         }
 
         Ok(())
@@ -141,6 +877,23 @@ pub struct TWAPOracle {
     pub _padding: [u8; 4],
 }
 
+impl TWAPOracle {
+    /// The price of a `side` order pegged `peg_offset_bps` away from the last
+    /// observation. For example, an offset of -50 prices the order 0.5% below
+    /// it. The price is rounded onto the `tick_size` grid, away from the other
+    /// side of the book.
+    pub fn pegged_price(&self, peg_offset_bps: i16, side: Side, tick_size: u64) -> u64 {
+        let price = ((self.last_observation as u128
+            * (MAX_BPS as i32 + peg_offset_bps as i32) as u128)
+            / MAX_BPS as u128) as u64;
+
+        match side {
+            Side::Buy => price - price % tick_size,
+            Side::Sell => price + (tick_size - price % tick_size) % tick_size,
+        }
+    }
+}
+
 #[zero_copy]
 pub struct OrderList {
     pub side: StoredSide,
@@ -153,20 +906,27 @@ pub struct OrderList {
 
 impl OrderList {
     pub fn iter(&self) -> OrderListIterator {
-        OrderListIterator::new(self)
+        OrderListIterator::new(self, None)
+    }
+
+    /// Like `iter`, but skips over any orders that have expired as of `slot`.
+    pub fn iter_unexpired(&self, slot: u64) -> OrderListIterator<'_> {
+        OrderListIterator::new(self, Some(slot))
     }
 }
 
 pub struct OrderListIterator<'a> {
     i: u8,
     orders: &'a [Order],
+    current_slot: Option<u64>,
 }
 
 impl<'a> OrderListIterator<'a> {
-    pub fn new(order_list: &'a OrderList) -> Self {
+    pub fn new(order_list: &'a OrderList, current_slot: Option<u64>) -> Self {
         Self {
             i: order_list.best_order_idx,
             orders: &order_list.orders,
+            current_slot,
         }
     }
 }
@@ -175,14 +935,20 @@ impl Iterator for OrderListIterator<'_> {
     type Item = (Order, u8);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let i = self.i;
+        loop {
+            let i = self.i;
+
+            if i == NULL || self.orders[i as usize].amount_in == 0 {
+                return None;
+            }
 
-        if i == NULL || self.orders[i as usize].amount_in == 0 {
-            None
-        } else {
             let order = self.orders[i as usize];
             self.i = order.next_idx;
-            Some((order, i))
+
+            match self.current_slot {
+                Some(slot) if order.is_expired(slot) => continue,
+                _ => return Some((order, i)),
+            }
         }
     }
 }
@@ -194,21 +960,11 @@ impl OrderList {
     /// If the order can be placed, debits the relevant tokens from the maker.
     pub fn insert_order(
         &mut self,
-        amount: u64,
-        price: u64,
-        ref_id: u32,
-        market_maker_index: u8,
+        mut order: Order,
         makers: &mut [MarketMaker; NUM_MARKET_MAKERS],
     ) -> Option<u8> {
-        let mut order = Order {
-            amount_in: amount,
-            price,
-            ref_id,
-            market_maker_index,
-            next_idx: NULL,
-            prev_idx: NULL,
-            _padding: Default::default(),
-        };
+        order.next_idx = NULL;
+        order.prev_idx = NULL;
 
         // Iterate until finding an order with an inferior price. At that point,
         // insert this order between it and the order from the previous iteration.
@@ -294,7 +1050,7 @@ impl OrderList {
         }
 
         self.debit_tokens(
-            order.amount_in,
+            order.amount_in + order.hidden_amount,
             &mut makers[order.market_maker_index as usize],
         );
 
@@ -324,6 +1080,260 @@ impl OrderList {
     pub fn delete_order(&mut self, i: u8, makers: &mut [MarketMaker; NUM_MARKET_MAKERS]) -> Order {
         let order = self.orders[i as usize];
 
+        self.unlink_order(i);
+
+        self.credit_tokens(
+            order.amount_in + order.hidden_amount,
+            &mut makers[order.market_maker_index as usize],
+        );
+
+        self.orders[i as usize] = Order::default();
+        self.free_bitmap.mark_free(i);
+
+        order
+    }
+
+    /// Amends the amount and price of the order in chunk `i`, settling the
+    /// difference in amount with its maker. The order keeps its place in the
+    /// queue unless its price changes or its amount grows, in which case it
+    /// moves behind every other order at its new price.
+    pub fn update_order(
+        &mut self,
+        i: u8,
+        amount: u64,
+        price: u64,
+        makers: &mut [MarketMaker; NUM_MARKET_MAKERS],
+    ) {
+        let order = self.orders[i as usize];
+        let maker = &mut makers[order.market_maker_index as usize];
+
+        if amount > order.amount_in {
+            self.debit_tokens(amount - order.amount_in, maker);
+        } else {
+            self.credit_tokens(order.amount_in - amount, maker);
+        }
+
+        self.orders[i as usize].amount_in = amount;
+        self.orders[i as usize].price = price;
+
+        if price != order.price || amount > order.amount_in {
+            self.unlink_order(i);
+            self.link_order(i);
+        }
+    }
+
+    /// Checks whether a taker can fill against the order in chunk `i`,
+    /// deleting it if it has expired and applying the taker's self-trade
+    /// behavior if it is their own.
+    fn can_fill_against(
+        &mut self,
+        i: u8,
+        taker: Taker,
+        slot: u64,
+        makers: &mut [MarketMaker; NUM_MARKET_MAKERS],
+    ) -> std::result::Result<bool, CLOBError> {
+        let order = self.orders[i as usize];
+
+        if order.is_expired(slot) {
+            self.delete_order(i, makers);
+            return Ok(false);
+        }
+
+        if makers[order.market_maker_index as usize].can_trade(taker.authority) {
+            match taker.self_trade_behavior {
+                SelfTradeBehavior::CancelResting => {
+                    self.delete_order(i, makers);
+                }
+                SelfTradeBehavior::SkipResting => {}
+                SelfTradeBehavior::AbortTake => return Err(CLOBError::SelfTrade),
+            }
+
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Fills `amount` of the tokens offered by the order in chunk `i`,
+    /// crediting its maker with the `amount_paid` it receives in exchange plus
+    /// a rebate of `maker_rebate_bps` of it. Returns the index of the next
+    /// order for the taker to fill against and the rebate.
+    ///
+    /// If nothing is left of the order, it is refilled from its hidden amount,
+    /// moving behind every other order at its price, or deleted if it has
+    /// none, since iterators treat an empty order as the end of the list.
+    fn fill_order(
+        &mut self,
+        i: u8,
+        amount: u64,
+        amount_paid: u64,
+        maker_rebate_bps: u16,
+        makers: &mut [MarketMaker; NUM_MARKET_MAKERS],
+    ) -> (u8, u64) {
+        self.orders[i as usize].amount_in -= amount;
+        let order = self.orders[i as usize];
+
+        let maker_rebate =
+            ((amount_paid as u128 * maker_rebate_bps as u128) / MAX_BPS as u128) as u64;
+
+        // Buyers are paid in base and sellers in quote
+        let maker = &mut makers[order.market_maker_index as usize];
+        match self.side.into() {
+            Side::Buy => maker.base_balance += amount_paid + maker_rebate,
+            Side::Sell => maker.quote_balance += amount_paid + maker_rebate,
+        };
+
+        if order.amount_in > 0 {
+            return (order.next_idx, maker_rebate);
+        }
+
+        if order.hidden_amount == 0 {
+            self.delete_order(i, makers);
+            return (order.next_idx, maker_rebate);
+        }
+
+        let slice_amount = std::cmp::min(order.slice_amount, order.hidden_amount);
+        self.orders[i as usize].amount_in = slice_amount;
+        self.orders[i as usize].hidden_amount -= slice_amount;
+
+        self.unlink_order(i);
+        self.link_order(i);
+
+        // The refilled order now comes after the rest of its price level, so
+        // the taker only gets back to it after filling against them
+        if order.next_idx != NULL && self.orders[order.next_idx as usize].price == order.price {
+            (order.next_idx, maker_rebate)
+        } else {
+            (i, maker_rebate)
+        }
+    }
+
+    /// Deletes every order belonging to the maker at `market_maker_index`,
+    /// crediting their tokens back to it. Returns how many orders were deleted.
+    pub fn delete_maker_orders(
+        &mut self,
+        market_maker_index: u8,
+        makers: &mut [MarketMaker; NUM_MARKET_MAKERS],
+    ) -> u8 {
+        let mut num_deleted = 0;
+
+        let mut i = self.best_order_idx;
+        while i != NULL {
+            let order = self.orders[i as usize];
+
+            if order.market_maker_index == market_maker_index {
+                self.delete_order(i, makers);
+                num_deleted += 1;
+            }
+
+            i = order.next_idx;
+        }
+
+        num_deleted
+    }
+
+    /// Deletes every order that has expired as of `slot`, crediting their
+    /// tokens back to their makers.
+    pub fn delete_expired_orders(
+        &mut self,
+        slot: u64,
+        makers: &mut [MarketMaker; NUM_MARKET_MAKERS],
+    ) {
+        let mut i = self.best_order_idx;
+
+        while i != NULL {
+            let order = self.orders[i as usize];
+
+            if order.is_expired(slot) {
+                self.delete_order(i, makers);
+            }
+
+            i = order.next_idx;
+        }
+    }
+
+    /// Moves every pegged order to its price relative to `oracle`'s last
+    /// observation, re-ranking it within the list. Pegged orders that end up
+    /// at the same price keep their relative time priority.
+    ///
+    /// A pegged order is kept at least a tick behind `opposing_best_price`, so
+    /// that it never crosses the book, and is cancelled if that or its peg
+    /// would leave it priced at zero.
+    pub fn reprice_pegged_orders(
+        &mut self,
+        oracle: &TWAPOracle,
+        tick_size: u64,
+        opposing_best_price: Option<u64>,
+        makers: &mut [MarketMaker; NUM_MARKET_MAKERS],
+    ) {
+        // We cannot re-link the orders while walking the list because a
+        // re-linked order may land further down it.
+        let pegged_orders = self
+            .iter()
+            .filter(|(order, _)| order.is_pegged())
+            .map(|(_, order_idx)| order_idx)
+            .collect::<Vec<_>>();
+
+        let side = self.side.into();
+
+        for order_idx in pegged_orders {
+            let order = self.orders[order_idx as usize];
+            let price = oracle.pegged_price(order.peg_offset_bps, side, tick_size);
+
+            let price = match opposing_best_price {
+                Some(opposing_best_price) if !self.is_price_better(opposing_best_price, price) => {
+                    price_behind(side, opposing_best_price, tick_size)
+                }
+                _ => price,
+            };
+
+            if price == 0 {
+                self.delete_order(order_idx, makers);
+                continue;
+            }
+
+            self.orders[order_idx as usize].price = price;
+
+            self.unlink_order(order_idx);
+            self.link_order(order_idx);
+        }
+    }
+
+    /// Links the order in chunk `i` into the list behind every order with an
+    /// equal or better price.
+    fn link_order(&mut self, i: u8) {
+        let price = self.orders[i as usize].price;
+
+        let mut prev_idx = NULL;
+        let mut next_idx = self.best_order_idx;
+        while next_idx != NULL && !self.is_price_better(price, self.orders[next_idx as usize].price)
+        {
+            prev_idx = next_idx;
+            next_idx = self.orders[next_idx as usize].next_idx;
+        }
+
+        if prev_idx == NULL {
+            self.best_order_idx = i;
+        } else {
+            self.orders[prev_idx as usize].next_idx = i;
+        }
+
+        if next_idx == NULL {
+            self.worst_order_idx = i;
+        } else {
+            self.orders[next_idx as usize].prev_idx = i;
+        }
+
+        let order = &mut self.orders[i as usize];
+        order.prev_idx = prev_idx;
+        order.next_idx = next_idx;
+    }
+
+    /// Removes the order in chunk `i` from the list without touching its
+    /// contents or the free bitmap.
+    fn unlink_order(&mut self, i: u8) {
+        let order = self.orders[i as usize];
+
         if i == self.best_order_idx {
             self.best_order_idx = order.next_idx;
         } else {
@@ -335,16 +1345,22 @@ impl OrderList {
         } else {
             self.orders[order.next_idx as usize].prev_idx = order.prev_idx;
         }
+    }
 
-        self.credit_tokens(
-            order.amount_in,
-            &mut makers[order.market_maker_index as usize],
-        );
-
-        self.orders[i as usize] = Order::default();
-        self.free_bitmap.mark_free(i);
+    /// Would an order at `price` make it onto the list, either into a free
+    /// chunk or by pushing out the worst order?
+    pub fn has_room_for(&self, price: u64) -> bool {
+        !self.free_bitmap.are_all_chunks_taken()
+            || self.is_price_better(price, self.orders[self.worst_order_idx as usize].price)
+    }
 
-        order
+    /// Would an order on the other side of the book priced at `price` cross
+    /// this list's best order?
+    pub fn is_crossed_by(&self, price: u64) -> bool {
+        match self.iter().next() {
+            Some((best_order, _)) => !self.is_price_better(price, best_order.price),
+            None => false,
+        }
     }
 
     /// Is `lhs` a better price than `rhs`?
@@ -361,11 +1377,31 @@ pub struct Order {
     pub next_idx: u8,
     pub prev_idx: u8,
     pub market_maker_index: u8,
-    pub _padding: [u8; 1],
+    pub is_pegged: u8,
     pub ref_id: u32,
     // if this order is filled, maker will receive (amount * price) / 1e9
     pub price: u64,
     pub amount_in: u64,
+    // the last slot in which this order can be filled, or 0 if it never expires
+    pub expiry_slot: u64,
+    // the part of this order that is kept off the book, and how much of it to
+    // move into `amount_in` each time `amount_in` is filled
+    pub hidden_amount: u64,
+    pub slice_amount: u64,
+    // if this order is pegged, `price` tracks the TWAP oracle's last
+    // observation offset by this many basis points
+    pub peg_offset_bps: i16,
+    pub _padding: [u8; 6],
+}
+
+impl Order {
+    pub fn is_expired(&self, slot: u64) -> bool {
+        self.expiry_slot != 0 && slot > self.expiry_slot
+    }
+
+    pub fn is_pegged(&self) -> bool {
+        self.is_pegged != 0
+    }
 }
 
 impl Default for Order {
@@ -374,17 +1410,77 @@ impl Default for Order {
             next_idx: NULL,
             prev_idx: NULL,
             market_maker_index: NULL,
-            _padding: [0],
+            is_pegged: 0,
             ref_id: 0,
             price: 0,
             amount_in: 0,
+            expiry_slot: 0,
+            hidden_amount: 0,
+            slice_amount: 0,
+            peg_offset_bps: 0,
+            _padding: Default::default(),
         }
     }
 }
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct LimitOrderParams {
+    pub side: Side,
+    pub amount_in: u64,
+    /// Ignored if the order is pegged
+    pub price: u64,
+    pub ref_id: u32,
+    pub expiry_slot: Option<u64>,
+    pub peg_offset_bps: Option<i16>,
+    pub order_type: OrderType,
+    /// How much more to keep off the book, refilling `amount_in` from it
+    /// each time `amount_in` is filled
+    pub hidden_amount: Option<u64>,
+}
+
 #[zero_copy]
 pub struct MarketMaker {
     pub base_balance: u64,
     pub quote_balance: u64,
+    // lamports held by the order book that are refunded when the seat is
+    // removed, if it was added under `MarketMakerDepositMode::Escrow`
+    pub deposit_lamports: u64,
     pub authority: Pubkey,
+    // who `authority` has offered this seat to, if anyone, until they accept
+    pub pending_authority: Pubkey,
+    // may manage orders, but not withdraw or change the seat
+    pub delegate: Pubkey,
+}
+
+impl MarketMaker {
+    pub fn can_trade(&self, key: Pubkey) -> bool {
+        key == self.authority || (self.delegate != Pubkey::default() && key == self.delegate)
+    }
+}
+
+/// The best price that a `side` order can have without crossing an opposing
+/// order at `opposing_price`, one tick behind it. The opposing order may
+/// predate the current tick size, so this rounds away from it onto the
+/// nearest tick rather than just stepping a tick. 0 if a buy can't go that
+/// low.
+fn price_behind(side: Side, opposing_price: u64, tick_size: u64) -> u64 {
+    match side {
+        Side::Buy => {
+            let price = opposing_price - 1;
+            price - price % tick_size
+        }
+        Side::Sell => {
+            let price = opposing_price + 1;
+            price + (tick_size - price % tick_size) % tick_size
+        }
+    }
+}
+
+fn div_ceil(numerator: u128, denominator: u128) -> u128 {
+    let quotient = numerator / denominator;
+    if quotient * denominator == numerator {
+        quotient
+    } else {
+        quotient + 1
+    }
 }
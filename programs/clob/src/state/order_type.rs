@@ -0,0 +1,13 @@
+use super::*;
+
+/// How a limit order should behave if it would cross the book.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OrderType {
+    /// Fill against the opposing orders it crosses and rest the remainder
+    Limit,
+    /// Reject the order if it would cross the best opposing order
+    PostOnly,
+    /// Reprice the order to one tick behind the best opposing order if it
+    /// would cross it
+    PostOnlySlide,
+}
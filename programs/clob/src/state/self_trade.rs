@@ -0,0 +1,21 @@
+use super::*;
+
+/// What to do when a taker would trade against a resting order placed by a
+/// market maker that they control.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTradeBehavior {
+    /// Cancel the resting order and keep matching past it
+    CancelResting,
+    /// Leave the resting order on the book and keep matching past it
+    SkipResting,
+    /// Fail the whole take
+    AbortTake,
+}
+
+/// Who a take is being made by, so that they can be kept from trading
+/// against their own orders.
+#[derive(Clone, Copy)]
+pub struct Taker {
+    pub authority: Pubkey,
+    pub self_trade_behavior: SelfTradeBehavior,
+}
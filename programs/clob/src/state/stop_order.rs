@@ -0,0 +1,104 @@
+use super::*;
+
+pub const NUM_STOP_ORDERS: usize = 32;
+// so that no one maker can fill up the stop orders for everyone else
+pub const MAX_STOP_ORDERS_PER_MARKET_MAKER: usize = 4;
+
+/// Which way the TWAP oracle's observation has to move for a stop order to
+/// trigger, relative to the side it trades on.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum StopOrderKind {
+    /// Triggers when the price moves against a position that the order
+    /// closes: a sell when the price falls, a buy when it rises
+    StopLoss,
+    /// Triggers when the price moves in favor of a position that the order
+    /// closes: a sell when the price rises, a buy when it falls
+    TakeProfit,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct StopOrderParams {
+    pub side: Side,
+    pub kind: StopOrderKind,
+    pub trigger_price: u64,
+    pub amount_in: u64,
+    /// The price of the limit order to place once triggered, or `None` to
+    /// take against the opposing orders instead
+    pub limit_price: Option<u64>,
+    pub ref_id: u32,
+    /// How far past the observation that triggers it, in bps, a stop order
+    /// without a `limit_price` may fill at
+    pub max_slippage_bps: u16,
+}
+
+/// An order that sits off the book, with its tokens escrowed from its
+/// maker's balance, until the TWAP oracle's observation reaches
+/// `trigger_price`.
+#[zero_copy]
+pub struct StopOrder {
+    pub trigger_price: u64,
+    pub amount_in: u64,
+    // the price of the limit order this becomes when triggered, or 0 for it
+    // to be a take against the opposing orders
+    pub limit_price: u64,
+    pub ref_id: u32,
+    // the fees when this was placed, so that they can't be changed on a stop
+    // order after the fact
+    pub taker_fee_in_bps: u16,
+    pub maker_rebate_bps: u16,
+    pub max_slippage_bps: u16,
+    pub market_maker_index: u8,
+    pub side: StoredSide,
+    // whether this triggers when the observation rises to `trigger_price`,
+    // rather than when it falls to it
+    pub triggers_above: u8,
+    pub _padding: [u8; 3],
+}
+
+impl StopOrder {
+    pub fn fees(&self) -> Fees {
+        Fees {
+            taker_fee_in_bps: self.taker_fee_in_bps,
+            maker_rebate_bps: self.maker_rebate_bps,
+            referral_share_bps: 0,
+        }
+    }
+
+    /// The worst price that a take triggered at `observation` may fill at.
+    pub fn take_limit_price(&self, observation: u64) -> u64 {
+        let slippage_bps = match self.side.into() {
+            Side::Buy => MAX_BPS + self.max_slippage_bps,
+            Side::Sell => MAX_BPS - self.max_slippage_bps,
+        };
+
+        let limit_price = (observation as u128 * slippage_bps as u128) / MAX_BPS as u128;
+
+        limit_price.min(u64::MAX as u128) as u64
+    }
+
+    pub fn is_triggered_by(&self, observation: u64) -> bool {
+        if self.triggers_above != 0 {
+            observation >= self.trigger_price
+        } else {
+            observation <= self.trigger_price
+        }
+    }
+}
+
+impl Default for StopOrder {
+    fn default() -> Self {
+        Self {
+            trigger_price: 0,
+            amount_in: 0,
+            limit_price: 0,
+            ref_id: 0,
+            taker_fee_in_bps: 0,
+            maker_rebate_bps: 0,
+            max_slippage_bps: 0,
+            market_maker_index: NULL,
+            side: Side::Buy.into(),
+            triggers_above: 0,
+            _padding: Default::default(),
+        }
+    }
+}
@@ -16,13 +16,13 @@ describe("YALOB", () => {
 
   const program = anchor.workspace.Clob as Program<Clob>;
 
-  it("Can be initialized", async () => {
-    const [globalState] = anchor.web3.PublicKey.findProgramAddressSync(
-      [anchor.utils.bytes.utf8.encode("WWCACOTMICMIBMHAFTTWYGHMB")],
-      program.programId
-    );
-    const admin = anchor.web3.Keypair.generate();
+  const [globalState] = anchor.web3.PublicKey.findProgramAddressSync(
+    [anchor.utils.bytes.utf8.encode("WWCACOTMICMIBMHAFTTWYGHMB")],
+    program.programId
+  );
+  const admin = anchor.web3.Keypair.generate();
 
+  before(async () => {
     await program.methods
       .initializeGlobalState(admin.publicKey)
       .accounts({
@@ -31,56 +31,38 @@ describe("YALOB", () => {
         systemProgram: anchor.web3.SystemProgram.programId,
       })
       .rpc();
+  });
 
-    const mintAuthority = anchor.web3.Keypair.generate();
-    const quote = await token.createMint(
-      provider.connection,
-      payer,
-      mintAuthority.publicKey,
-      mintAuthority.publicKey,
-      8
-    );
-    const base = await token.createMint(
-      provider.connection,
-      payer,
-      mintAuthority.publicKey,
-      mintAuthority.publicKey,
-      8
-    );
-
-    const [orderBook] = anchor.web3.PublicKey.findProgramAddressSync(
-      [
-        anchor.utils.bytes.utf8.encode("order_book"),
-        base.toBuffer(),
-        quote.toBuffer(),
-      ],
-      program.programId
-    );
-
-    const baseVault = await token.getAssociatedTokenAddress(
-      base,
-      orderBook,
-      true
-    );
+  // A fresh order book with funded market makers at indices 0 and 1
+  async function createMarket() {
+    const book = await createOrderBook(program, connection, payer);
+
+    const makers = [];
+    for (const index of [0, 1]) {
+      makers.push(
+        await generateMarketMaker(
+          index,
+          program,
+          connection,
+          payer,
+          globalState,
+          book.orderBook,
+          book.baseVault,
+          book.quoteVault,
+          book.base,
+          book.quote,
+          book.mintAuthority,
+          admin
+        )
+      );
+    }
 
-    const quoteVault = await token.getAssociatedTokenAddress(
-      quote,
-      orderBook,
-      true
-    );
+    return { ...book, makers };
+  }
 
-    await program.methods
-      .initializeOrderBook()
-      .accounts({
-        orderBook,
-        payer: payer.publicKey,
-        systemProgram: anchor.web3.SystemProgram.programId,
-        base,
-        quote,
-        baseVault,
-        quoteVault,
-      })
-      .rpc();
+  it("Can be initialized", async () => {
+    const { mintAuthority, base, quote, orderBook, baseVault, quoteVault } =
+      await createOrderBook(program, connection, payer);
 
     const [mm0, mm0Base, mm0Quote] = await generateMarketMaker(
       0, // reside at 0th index
@@ -125,11 +107,16 @@ describe("YALOB", () => {
         new anchor.BN(100), // amount
         new anchor.BN(1e9), // price
         12, // ref id
-        0 // mm index
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
       )
       .accounts({
         authority: mm0.publicKey,
         orderBook,
+        globalState,
       })
       .signers([mm0])
       .rpc();
@@ -153,11 +140,16 @@ describe("YALOB", () => {
         new anchor.BN(101), // amount
         new anchor.BN(1e9 + 2), // price
         13, // ref id
-        1 // mm index
+        1, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
       )
       .accounts({
         authority: mm1.publicKey,
         orderBook,
+        globalState,
       })
       .signers([mm1])
       .rpc();
@@ -168,11 +160,16 @@ describe("YALOB", () => {
         new anchor.BN(102), // amount
         new anchor.BN(1e9 + 1), // price
         14, // ref id
-        1 // mm index
+        1, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
       )
       .accounts({
         authority: mm1.publicKey,
         orderBook,
+        globalState,
       })
       .signers([mm1])
       .rpc();
@@ -215,17 +212,74 @@ describe("YALOB", () => {
     // should get their tokens back
     assert(mm0BalsAfter.quoteBalance.eq(mm0BalsBefore.quoteBalance));
 
+    let mm1BalsBefore = await program.methods
+      .getMarketMakerBalances(mm1.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+
+    orderIndex = await program.methods
+      .getOrderIndex({ buy: {} }, 14, 1)
+      .accounts({
+        orderBook,
+      })
+      .view();
+
+    await program.methods
+      .updateLimitOrder(
+        { buy: {} },
+        orderIndex,
+        1, // mm index
+        new anchor.BN(50), // new amount
+        null // new price
+      )
+      .accounts({
+        orderBook,
+        authority: mm1.publicKey,
+      })
+      .signers([mm1])
+      .rpc();
+
+    let mm1BalsAfter = await program.methods
+      .getMarketMakerBalances(mm1.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+
+    // should get the difference back
+    assert(
+      mm1BalsAfter.quoteBalance.eq(
+        mm1BalsBefore.quoteBalance.add(new anchor.BN(52))
+      )
+    );
+
+    buys = await program.methods
+      .getBestOrders({ buy: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert(buys[1].amount.eq(new anchor.BN(50)));
+    assert(buys[1].price.eq(new anchor.BN(1e9 + 1)));
+
     await program.methods
       .submitLimitOrder(
         { sell: {} },
         new anchor.BN(300), // amount
         new anchor.BN(2e9), // price
         15, // ref id
-        0 // mm index
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
       )
       .accounts({
         authority: mm0.publicKey,
         orderBook,
+        globalState,
       })
       .signers([mm0])
       .rpc();
@@ -275,7 +329,10 @@ describe("YALOB", () => {
       .submitTakeOrder(
         { buy: {} },
         new anchor.BN(100),
-        new anchor.BN(49) // allow round down to 49 bcuz taker fees
+        new anchor.BN(49), // allow round down to 49 bcuz taker fees
+        null, // limit price
+        { abortTake: {} }, // self-trade behavior
+        false // fill or kill
       )
       .accounts({
         globalState,
@@ -286,6 +343,7 @@ describe("YALOB", () => {
         authority: mm1.publicKey,
         orderBook,
         tokenProgram: token.TOKEN_PROGRAM_ID,
+        referrer: null,
       })
       .signers([mm1])
       .rpc();
@@ -327,6 +385,35 @@ describe("YALOB", () => {
       )
     );
 
+    // the only sell is at 2, so nothing should fill below that
+    await program.methods
+      .submitTakeOrder(
+        { buy: {} },
+        new anchor.BN(100),
+        new anchor.BN(0),
+        new anchor.BN(1.5e9), // limit price
+        { abortTake: {} }, // self-trade behavior
+        false // fill or kill
+      )
+      .accounts({
+        globalState,
+        userBaseAccount: mm1Base,
+        userQuoteAccount: mm1Quote,
+        baseVault,
+        quoteVault,
+        authority: mm1.publicKey,
+        orderBook,
+        tokenProgram: token.TOKEN_PROGRAM_ID,
+        referrer: null,
+      })
+      .signers([mm1])
+      .rpc();
+
+    assert.equal(
+      Number((await token.getAccount(connection, mm1Quote)).amount),
+      Number(mm1QuoteTokenBalanceAfter)
+    );
+
     let ob = await program.account.orderBook.fetch(orderBook);
     assert(ob.quoteFeesSweepable.eq(new anchor.BN(1)));
 
@@ -366,11 +453,16 @@ describe("YALOB", () => {
           new anchor.BN(1000), // amount
           new anchor.BN(3e9), // this price shouldn't affect anything
           50 + i, // ref id
-          0 // mm index
+          0, // mm index
+          null, // expiry slot
+          null, // peg offset
+          { limit: {} }, // order type
+          null // hidden amount
         )
         .accounts({
           authority: mm0.publicKey,
           orderBook,
+          globalState,
         })
         .signers([mm0])
         .rpc();
@@ -443,64 +535,2604 @@ describe("YALOB", () => {
         new anchor.BN(101), // amount
         new anchor.BN(2e9 - 100), // price
         13, // ref id
-        1 // mm index
+        1, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
       )
       .accounts({
         authority: mm1.publicKey,
         orderBook,
+        globalState,
       })
       .signers([mm1])
       .rpc();
 
-    // this is a twap test that makes the tests longer to run, uncomment it
-    // if you wish
+    let mm1BalsBeforeCross = await program.methods
+      .getMarketMakerBalances(mm1.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
 
-    // this should fill up the book
-    /* for (let i = 0; i < 122; i++) { */
-    /*   await program.methods */
-    /*     .submitLimitOrder( */
-    /*       { sell: {} }, */
-    /*       new anchor.BN(1000), // amount */
-    /*       new anchor.BN(3e9), // this price shouldn't affect anything */
-    /*       60 + i, // ref id */
-    /*       0 // mm index */
-    /*     ) */
-    /*     .accounts({ */
-    /*       authority: mm0.publicKey, */
-    /*       orderBook, */
-    /*     }) */
-    /*     .signers([mm0]) */
-    /*     .rpc(); */
-    /* } */
+    // this crosses the sell at 2, so it should fill like a take order instead
+    // of resting on the book
+    await program.methods
+      .submitLimitOrder(
+        { buy: {} },
+        new anchor.BN(100), // amount
+        new anchor.BN(2e9), // price
+        16, // ref id
+        1, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm1.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm1])
+      .rpc();
 
-    /* twap = await program.methods */
-    /*   .getTwap() */
-    /*   .accounts({ */
-    /*     orderBook, */
-    /*   }) */
-    /*   .view(); */
+    let mm1BalsAfterCross = await program.methods
+      .getMarketMakerBalances(mm1.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
 
-    /* slotsPassed = twap.lastUpdatedSlot.sub(endingSlot); */
+    assert(
+      mm1BalsAfterCross.quoteBalance.eq(
+        mm1BalsBeforeCross.quoteBalance.sub(new anchor.BN(100))
+      )
+    );
+    assert(
+      mm1BalsAfterCross.baseBalance.eq(
+        mm1BalsBeforeCross.baseBalance.add(new anchor.BN(49)) // taker fee of 1
+      )
+    );
 
-    /* assert(slotsPassed.gtn(100)); */
-    /* assert(slotsPassed.ltn(200)); */
+    buys = await program.methods
+      .getBestOrders({ buy: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(buys.length, 3);
 
-    /* aggregatorDifference = twap.observationAggregator.sub( */
-    /*   endingObservationAggregator */
-    /* ); */
+    sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert(sells[0].amount.eq(new anchor.BN(202)));
+    assert(sells[0].price.eq(new anchor.BN(2e9)));
 
-    /* twapPrice = aggregatorDifference.div(slotsPassed); */
+    // buying exactly 10 at a price of 2 should cost 20, plus a taker fee
+    // rounded up to 1
+    let mm1QuoteBeforeExactOut = (await token.getAccount(connection, mm1Quote))
+      .amount;
 
-    /* let minPrice = new anchor.BN(2e9 - 1e8); // 1.9 */
-    /* let maxPrice = new anchor.BN(2e9); */
+    await program.methods
+      .submitTakeOrderExactOut(
+        { buy: {} },
+        new anchor.BN(10), // amount out
+        new anchor.BN(21), // max in
+        { abortTake: {} } // self-trade behavior
+      )
+      .accounts({
+        globalState,
+        userBaseAccount: mm1Base,
+        userQuoteAccount: mm1Quote,
+        baseVault,
+        quoteVault,
+        authority: mm1.publicKey,
+        orderBook,
+        tokenProgram: token.TOKEN_PROGRAM_ID,
+        referrer: null,
+      })
+      .signers([mm1])
+      .rpc();
 
-    /* assert(twapPrice.gt(minPrice)); */
-    /* assert(twapPrice.lt(maxPrice)); */
-  });
-});
+    assert.equal(
+      Number((await token.getAccount(connection, mm1Quote)).amount),
+      Number(mm1QuoteBeforeExactOut) - 21
+    );
 
-const BASE_AMOUNT = 1_000_000_000;
-const QUOTE_AMOUNT = 1_000_000_000;
+    sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert(sells[0].amount.eq(new anchor.BN(192)));
+
+    // this would cross the sell at 2, so it should slide to one tick below it
+    await program.methods
+      .submitLimitOrder(
+        { buy: {} },
+        new anchor.BN(100), // amount
+        new anchor.BN(3e9), // price
+        20, // ref id
+        1, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { postOnlySlide: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm1.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm1])
+      .rpc();
+
+    buys = await program.methods
+      .getBestOrders({ buy: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(buys.length, 4);
+    assert(buys[0].amount.eq(new anchor.BN(100)));
+    assert(buys[0].price.eq(new anchor.BN(2e9 - 1)));
+
+    // a rejected order shouldn't stop the rest of the batch from landing
+    await program.methods
+      .submitLimitOrders(
+        0, // mm index
+        [
+          {
+            side: { sell: {} },
+            amountIn: new anchor.BN(0),
+            price: new anchor.BN(4e9),
+            refId: 17,
+            expirySlot: null,
+            pegOffsetBps: null,
+            orderType: { limit: {} },
+            hiddenAmount: null,
+          },
+          {
+            side: { sell: {} },
+            amountIn: new anchor.BN(500),
+            price: new anchor.BN(4e9),
+            refId: 18,
+            expirySlot: null,
+            pegOffsetBps: null,
+            orderType: { limit: {} },
+            hiddenAmount: null,
+          },
+        ]
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 7);
+    assert(sells[6].amount.eq(new anchor.BN(500)));
+    assert(sells[6].price.eq(new anchor.BN(4e9)));
+
+    // only the first 100 of this should show, with the other 200 hidden
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(100), // amount
+        new anchor.BN(4e9), // price
+        21, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        new anchor.BN(200) // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 8);
+    assert(sells[7].amount.eq(new anchor.BN(100)));
+
+    await program.methods
+      .cancelAllOrders(0, { sell: {} })
+      .accounts({
+        orderBook,
+        authority: mm0.publicKey,
+      })
+      .signers([mm0])
+      .rpc();
+
+    sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 0);
+
+    // mm0 has no buys, so mm1's should be untouched
+    buys = await program.methods
+      .getBestOrders({ buy: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(buys.length, 4);
+
+    await program.methods
+      .replaceOrders(
+        1, // mm index
+        [
+          {
+            side: { buy: {} },
+            amountIn: new anchor.BN(200),
+            price: new anchor.BN(1e9 + 5),
+            refId: 19,
+            expirySlot: null,
+            pegOffsetBps: null,
+            orderType: { limit: {} },
+            hiddenAmount: null,
+          },
+        ]
+      )
+      .accounts({
+        authority: mm1.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm1])
+      .rpc();
+
+    buys = await program.methods
+      .getBestOrders({ buy: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(buys.length, 1);
+    assert(buys[0].amount.eq(new anchor.BN(200)));
+    assert(buys[0].price.eq(new anchor.BN(1e9 + 5)));
+
+    const mm1BalsBeforeReplace = await program.methods
+      .getMarketMakerBalances(mm1.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+
+    // the second order is too small, so mm1 should keep its old quotes
+    await assertFailsWith(
+      program.methods
+        .replaceOrders(
+          1, // mm index
+          [
+            {
+              side: { buy: {} },
+              amountIn: new anchor.BN(300),
+              price: new anchor.BN(1e9 + 6),
+              refId: 20,
+              expirySlot: null,
+              pegOffsetBps: null,
+              orderType: { limit: {} },
+              hiddenAmount: null,
+            },
+            {
+              side: { buy: {} },
+              amountIn: new anchor.BN(0),
+              price: new anchor.BN(1e9 + 6),
+              refId: 21,
+              expirySlot: null,
+              pegOffsetBps: null,
+              orderType: { limit: {} },
+              hiddenAmount: null,
+            },
+          ]
+        )
+        .accounts({
+          authority: mm1.publicKey,
+          orderBook,
+          globalState,
+        })
+        .signers([mm1])
+        .rpc(),
+      "MinLimitAmountNotMet"
+    );
+
+    buys = await program.methods
+      .getBestOrders({ buy: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(buys.length, 1);
+    assert(buys[0].amount.eq(new anchor.BN(200)));
+    assert(buys[0].price.eq(new anchor.BN(1e9 + 5)));
+
+    const mm1BalsAfterReplace = await program.methods
+      .getMarketMakerBalances(mm1.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert(
+      mm1BalsAfterReplace.quoteBalance.eq(mm1BalsBeforeReplace.quoteBalance)
+    );
+    assert(
+      mm1BalsAfterReplace.baseBalance.eq(mm1BalsBeforeReplace.baseBalance)
+    );
+
+    let mm0BalsBeforeStop = await program.methods
+      .getMarketMakerBalances(mm0.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+
+    // a stop order's tokens are escrowed until it triggers or is cancelled
+    await program.methods
+      .submitStopOrder(
+        { sell: {} },
+        { stopLoss: {} },
+        new anchor.BN(1), // trigger price
+        new anchor.BN(100), // amount
+        null, // limit price
+        22, // ref id
+        0, // mm index
+        0 // max slippage
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    let mm0BalsAfterStop = await program.methods
+      .getMarketMakerBalances(mm0.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert(
+      mm0BalsAfterStop.baseBalance.eq(
+        mm0BalsBeforeStop.baseBalance.sub(new anchor.BN(100))
+      )
+    );
+
+    await program.methods
+      .cancelStopOrder(
+        0, // stop order index
+        0 // mm index
+      )
+      .accounts({
+        orderBook,
+        authority: mm0.publicKey,
+      })
+      .signers([mm0])
+      .rpc();
+
+    mm0BalsAfterStop = await program.methods
+      .getMarketMakerBalances(mm0.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert(mm0BalsAfterStop.baseBalance.eq(mm0BalsBeforeStop.baseBalance));
+
+    let mm1BalsBeforeRemoval = await program.methods
+      .getMarketMakerBalances(mm1.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+    let mm1QuoteBeforeRemoval = (await token.getAccount(connection, mm1Quote))
+      .amount;
+
+    // removing a market maker should cancel its buy and pay everything out
+    await program.methods
+      .removeMarketMaker(1)
+      .accounts({
+        orderBook,
+        authority: mm1.publicKey,
+        baseTo: mm1Base,
+        quoteTo: mm1Quote,
+        baseVault,
+        quoteVault,
+        tokenProgram: token.TOKEN_PROGRAM_ID,
+      })
+      .signers([mm1])
+      .rpc();
+
+    buys = await program.methods
+      .getBestOrders({ buy: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(buys.length, 0);
+
+    assert.equal(
+      Number((await token.getAccount(connection, mm1Quote)).amount),
+      Number(mm1QuoteBeforeRemoval) +
+        mm1BalsBeforeRemoval.quoteBalance.toNumber() +
+        200 // the cancelled buy
+    );
+
+    // hand mm0's seat to a new authority, who has to accept it
+    const newMm0 = anchor.web3.Keypair.generate();
+
+    await program.methods
+      .transferMarketMaker(0, newMm0.publicKey, true)
+      .accounts({
+        orderBook,
+        authority: mm0.publicKey,
+      })
+      .signers([mm0])
+      .rpc();
+
+    ob = await program.account.orderBook.fetch(orderBook);
+    assert.ok(ob.marketMakers[0].authority.equals(mm0.publicKey));
+    assert.ok(ob.marketMakers[0].pendingAuthority.equals(newMm0.publicKey));
+
+    await program.methods
+      .acceptMarketMaker(0)
+      .accounts({
+        orderBook,
+        authority: newMm0.publicKey,
+      })
+      .signers([newMm0])
+      .rpc();
+
+    ob = await program.account.orderBook.fetch(orderBook);
+    assert.ok(ob.marketMakers[0].authority.equals(newMm0.publicKey));
+
+    // and give it straight back
+    await program.methods
+      .transferMarketMaker(0, mm0.publicKey, false)
+      .accounts({
+        orderBook,
+        authority: newMm0.publicKey,
+      })
+      .signers([newMm0])
+      .rpc();
+
+    // let a hot key manage mm0's orders
+    const mm0Delegate = anchor.web3.Keypair.generate();
+
+    await program.methods
+      .setMarketMakerDelegate(0, mm0Delegate.publicKey)
+      .accounts({
+        orderBook,
+        authority: mm0.publicKey,
+      })
+      .signers([mm0])
+      .rpc();
+
+    ob = await program.account.orderBook.fetch(orderBook);
+    assert.ok(ob.marketMakers[0].delegate.equals(mm0Delegate.publicKey));
+
+    await program.methods
+      .cancelAllOrders(0, { buy: {} })
+      .accounts({
+        orderBook,
+        authority: mm0Delegate.publicKey,
+      })
+      .signers([mm0Delegate])
+      .rpc();
+
+    // but it can't take the seat's tokens or change who holds it
+    await assertFailsWith(
+      program.methods
+        .withdrawBalance(0, new anchor.BN(1), new anchor.BN(1))
+        .accounts({
+          orderBook,
+          authority: mm0Delegate.publicKey,
+          baseTo: mm0Base,
+          quoteTo: mm0Quote,
+          baseVault,
+          quoteVault,
+          tokenProgram: token.TOKEN_PROGRAM_ID,
+        })
+        .signers([mm0Delegate])
+        .rpc(),
+      "UnauthorizedMarketMaker"
+    );
+
+    await assertFailsWith(
+      program.methods
+        .transferMarketMaker(0, mm0Delegate.publicKey, false)
+        .accounts({
+          orderBook,
+          authority: mm0Delegate.publicKey,
+        })
+        .signers([mm0Delegate])
+        .rpc(),
+      "UnauthorizedMarketMaker"
+    );
+
+    await assertFailsWith(
+      program.methods
+        .removeMarketMaker(0)
+        .accounts({
+          orderBook,
+          authority: mm0Delegate.publicKey,
+          baseTo: mm0Base,
+          quoteTo: mm0Quote,
+          baseVault,
+          quoteVault,
+          tokenProgram: token.TOKEN_PROGRAM_ID,
+        })
+        .signers([mm0Delegate])
+        .rpc(),
+      "UnauthorizedMarketMaker"
+    );
+
+    await assertFailsWith(
+      program.methods
+        .setMarketMakerDelegate(0, anchor.web3.Keypair.generate().publicKey)
+        .accounts({
+          orderBook,
+          authority: mm0Delegate.publicKey,
+        })
+        .signers([mm0Delegate])
+        .rpc(),
+      "UnauthorizedMarketMaker"
+    );
+
+    // revoking the delegate locks it out of the seat entirely
+    await program.methods
+      .setMarketMakerDelegate(0, null)
+      .accounts({
+        orderBook,
+        authority: mm0.publicKey,
+      })
+      .signers([mm0])
+      .rpc();
+
+    ob = await program.account.orderBook.fetch(orderBook);
+    assert.ok(
+      ob.marketMakers[0].delegate.equals(anchor.web3.PublicKey.default)
+    );
+
+    await assertFailsWith(
+      program.methods
+        .cancelAllOrders(0, { buy: {} })
+        .accounts({
+          orderBook,
+          authority: mm0Delegate.publicKey,
+        })
+        .signers([mm0Delegate])
+        .rpc(),
+      "UnauthorizedMarketMaker"
+    );
+
+    // this is a twap test that makes the tests longer to run, uncomment it
+    // if you wish
+
+    // this should fill up the book
+    /* for (let i = 0; i < 122; i++) { */
+    /*   await program.methods */
+    /*     .submitLimitOrder( */
+    /*       { sell: {} }, */
+    /*       new anchor.BN(1000), // amount */
+    /*       new anchor.BN(3e9), // this price shouldn't affect anything */
+    /*       60 + i, // ref id */
+    /*       0, // mm index */
+    /*       null, // expiry slot */
+    /*       null, // peg offset */
+    /*       { limit: {} }, // order type */
+    /*       null // hidden amount */
+    /*     ) */
+    /*     .accounts({ */
+    /*       authority: mm0.publicKey, */
+    /*       orderBook, */
+    /*       globalState, */
+    /*     }) */
+    /*     .signers([mm0]) */
+    /*     .rpc(); */
+    /* } */
+
+    /* twap = await program.methods */
+    /*   .getTwap() */
+    /*   .accounts({ */
+    /*     orderBook, */
+    /*   }) */
+    /*   .view(); */
+
+    /* slotsPassed = twap.lastUpdatedSlot.sub(endingSlot); */
+
+    /* assert(slotsPassed.gtn(100)); */
+    /* assert(slotsPassed.ltn(200)); */
+
+    /* aggregatorDifference = twap.observationAggregator.sub( */
+    /*   endingObservationAggregator */
+    /* ); */
+
+    /* twapPrice = aggregatorDifference.div(slotsPassed); */
+
+    /* let minPrice = new anchor.BN(2e9 - 1e8); // 1.9 */
+    /* let maxPrice = new anchor.BN(2e9); */
+
+    /* assert(twapPrice.gt(minPrice)); */
+    /* assert(twapPrice.lt(maxPrice)); */
+  });
+
+  it("Reprices pegged orders as the TWAP moves", async () => {
+    const {
+      orderBook,
+      baseVault,
+      quoteVault,
+      makers: [[mm0, mm0Base, mm0Quote], [mm1]],
+    } = await createMarket();
+
+    await program.methods
+      .submitLimitOrder(
+        { buy: {} },
+        new anchor.BN(400), // amount
+        new anchor.BN(1.99e9), // price
+        0, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(1000), // amount
+        new anchor.BN(3e9), // price
+        1, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    // the first observation is the midpoint, 2.495, so this is priced 22%
+    // below it at 1.9461
+    await program.methods
+      .submitLimitOrder(
+        { buy: {} },
+        new anchor.BN(500), // amount
+        new anchor.BN(0), // price, ignored
+        2, // ref id
+        1, // mm index
+        null, // expiry slot
+        -2200, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm1.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm1])
+      .rpc();
+
+    let buys = await program.methods
+      .getBestOrders({ buy: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(buys.length, 2);
+    assert(buys[0].price.eq(new anchor.BN(1.99e9)));
+    assert(buys[1].amount.eq(new anchor.BN(500)));
+    assert(buys[1].price.eq(new anchor.BN(1_946_100_000)));
+
+    // moving the offer up to 4 drags the observation up, and the pegged buy
+    // with it, until the pegged buy overtakes the one at 1.99
+    await program.methods
+      .cancelAllOrders(0, { sell: {} })
+      .accounts({
+        orderBook,
+        authority: mm0.publicKey,
+      })
+      .signers([mm0])
+      .rpc();
+
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(1000), // amount
+        new anchor.BN(4e9), // price
+        3, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    let ob = await program.account.orderBook.fetch(orderBook);
+    let pegged = ob.buys.orders[ob.buys.bestOrderIdx];
+    for (let i = 0; i < 10 && !pegged.isPegged; i++) {
+      // mm1 has no sells, so this only updates the oracle
+      await program.methods
+        .cancelAllOrders(1, { sell: {} })
+        .accounts({
+          orderBook,
+          authority: mm1.publicKey,
+        })
+        .signers([mm1])
+        .rpc();
+
+      ob = await program.account.orderBook.fetch(orderBook);
+      pegged = ob.buys.orders[ob.buys.bestOrderIdx];
+    }
+
+    assert.equal(pegged.isPegged, 1);
+    assert(pegged.price.gt(new anchor.BN(1.99e9)));
+    assert(
+      pegged.price.eq(
+        ob.twapOracle.lastObservation.muln(7800).divn(10000) // 10000 - 2200
+      )
+    );
+    assert(ob.buys.orders[pegged.nextIdx].price.eq(new anchor.BN(1.99e9)));
+
+    // a sell should fill against the pegged buy at wherever it is now
+    const mm0QuoteBefore = (await token.getAccount(connection, mm0Quote))
+      .amount;
+
+    await program.methods
+      .submitTakeOrder(
+        { sell: {} },
+        new anchor.BN(100),
+        new anchor.BN(0),
+        null, // limit price
+        { abortTake: {} }, // self-trade behavior
+        false // fill or kill
+      )
+      .accounts({
+        globalState,
+        userBaseAccount: mm0Base,
+        userQuoteAccount: mm0Quote,
+        baseVault,
+        quoteVault,
+        authority: mm0.publicKey,
+        orderBook,
+        tokenProgram: token.TOKEN_PROGRAM_ID,
+        referrer: null,
+      })
+      .signers([mm0])
+      .rpc();
+
+    ob = await program.account.orderBook.fetch(orderBook);
+    pegged = ob.buys.orders[ob.buys.bestOrderIdx];
+    assert.equal(pegged.isPegged, 1);
+
+    // 99 of the 100 is left after the taker fee
+    const expectedOut = pegged.price.muln(99).div(new anchor.BN(1e9));
+    assert.equal(
+      Number((await token.getAccount(connection, mm0Quote)).amount),
+      Number(mm0QuoteBefore) + expectedOut.toNumber()
+    );
+    assert(pegged.amountIn.eq(new anchor.BN(500).sub(expectedOut)));
+  });
+  it("Fills partially against orders priced away from 1", async () => {
+    const {
+      orderBook,
+      baseVault,
+      quoteVault,
+      makers: [
+        [mm0, mm0Base, mm0Quote],
+        [mm1, mm1Base, mm1Quote],
+      ],
+    } = await createMarket();
+
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(100), // amount
+        new anchor.BN(2e9), // price
+        0, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    let mm0BalsBefore = await program.methods
+      .getMarketMakerBalances(mm0.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+    const mm1BaseBefore = (await token.getAccount(connection, mm1Base)).amount;
+    const mm1QuoteBefore = (await token.getAccount(connection, mm1Quote))
+      .amount;
+
+    // 499 is left after the taker fee, but the sell can only absorb 200 of it
+    await program.methods
+      .submitTakeOrder(
+        { buy: {} },
+        new anchor.BN(500),
+        new anchor.BN(0),
+        null, // limit price
+        { abortTake: {} }, // self-trade behavior
+        false // fill or kill
+      )
+      .accounts({
+        globalState,
+        userBaseAccount: mm1Base,
+        userQuoteAccount: mm1Quote,
+        baseVault,
+        quoteVault,
+        authority: mm1.publicKey,
+        orderBook,
+        tokenProgram: token.TOKEN_PROGRAM_ID,
+        referrer: null,
+      })
+      .signers([mm1])
+      .rpc();
+
+    assert.equal(
+      Number((await token.getAccount(connection, mm1Base)).amount),
+      Number(mm1BaseBefore) + 100
+    );
+    assert.equal(
+      Number((await token.getAccount(connection, mm1Quote)).amount),
+      Number(mm1QuoteBefore) - 201 // 200 plus a taker fee of 1
+    );
+
+    let mm0BalsAfter = await program.methods
+      .getMarketMakerBalances(mm0.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert(
+      mm0BalsAfter.quoteBalance.eq(
+        mm0BalsBefore.quoteBalance.add(new anchor.BN(200))
+      )
+    );
+
+    // a sell crossing a buy of 200 at 2 should only fill 100 of itself
+    await program.methods
+      .submitLimitOrder(
+        { buy: {} },
+        new anchor.BN(200), // amount
+        new anchor.BN(2e9), // price
+        1, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    mm0BalsBefore = await program.methods
+      .getMarketMakerBalances(mm0.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+    const mm1BalsBefore = await program.methods
+      .getMarketMakerBalances(mm1.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+
+    // 299 is left after the taker fee, of which the buy absorbs 100
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(300), // amount
+        new anchor.BN(2e9), // price
+        2, // ref id
+        1, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm1.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm1])
+      .rpc();
+
+    mm0BalsAfter = await program.methods
+      .getMarketMakerBalances(mm0.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert(
+      mm0BalsAfter.baseBalance.eq(
+        mm0BalsBefore.baseBalance.add(new anchor.BN(100))
+      )
+    );
+
+    const mm1BalsAfter = await program.methods
+      .getMarketMakerBalances(mm1.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert(
+      mm1BalsAfter.quoteBalance.eq(
+        mm1BalsBefore.quoteBalance.add(new anchor.BN(200))
+      )
+    );
+    // 101 was spent, and the other 199 rests on the book
+    assert(
+      mm1BalsAfter.baseBalance.eq(
+        mm1BalsBefore.baseBalance.sub(new anchor.BN(300))
+      )
+    );
+
+    const buys = await program.methods
+      .getBestOrders({ buy: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(buys.length, 0);
+
+    const sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 1);
+    assert(sells[0].amount.eq(new anchor.BN(199)));
+    assert(sells[0].price.eq(new anchor.BN(2e9)));
+
+    const ob = await program.account.orderBook.fetch(orderBook);
+    assert(ob.baseFeesSweepable.eq(new anchor.BN(1)));
+  });
+
+  it("Keeps takers from trading against their own orders", async () => {
+    const {
+      orderBook,
+      baseVault,
+      quoteVault,
+      makers: [
+        [mm0, mm0Base, mm0Quote],
+        [mm1, mm1Base, mm1Quote],
+      ],
+    } = await createMarket();
+
+    for (const [mm, mmIndex, price] of [
+      [mm0, 0, 1e9],
+      [mm1, 1, 2e9],
+    ] as [anchor.web3.Keypair, number, number][]) {
+      await program.methods
+        .submitLimitOrder(
+          { sell: {} },
+          new anchor.BN(100), // amount
+          new anchor.BN(price), // price
+          0, // ref id
+          mmIndex, // mm index
+          null, // expiry slot
+          null, // peg offset
+          { limit: {} }, // order type
+          null // hidden amount
+        )
+        .accounts({
+          authority: mm.publicKey,
+          orderBook,
+          globalState,
+        })
+        .signers([mm])
+        .rpc();
+    }
+
+    const takeAsMm0 = (selfTradeBehavior) =>
+      program.methods
+        .submitTakeOrder(
+          { buy: {} },
+          new anchor.BN(100),
+          new anchor.BN(0),
+          null, // limit price
+          selfTradeBehavior,
+          false // fill or kill
+        )
+        .accounts({
+          globalState,
+          userBaseAccount: mm0Base,
+          userQuoteAccount: mm0Quote,
+          baseVault,
+          quoteVault,
+          authority: mm0.publicKey,
+          orderBook,
+          tokenProgram: token.TOKEN_PROGRAM_ID,
+          referrer: null,
+        })
+        .signers([mm0])
+        .rpc();
+
+    // mm0's own sell at 1 is the best one
+    await assertFailsWith(takeAsMm0({ abortTake: {} }), "SelfTrade");
+
+    // 99 is left after the taker fee, which buys 49 at 2
+    await takeAsMm0({ skipResting: {} });
+
+    let sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 2);
+    assert(sells[0].amount.eq(new anchor.BN(100)));
+    assert(sells[0].price.eq(new anchor.BN(1e9)));
+    assert(sells[1].amount.eq(new anchor.BN(51)));
+
+    const mm0BalsBefore = await program.methods
+      .getMarketMakerBalances(mm0.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+
+    await takeAsMm0({ cancelResting: {} });
+
+    sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 1);
+    assert(sells[0].amount.eq(new anchor.BN(2)));
+    assert(sells[0].price.eq(new anchor.BN(2e9)));
+
+    // the cancelled sell's tokens go back to mm0
+    const mm0BalsAfter = await program.methods
+      .getMarketMakerBalances(mm0.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert(
+      mm0BalsAfter.baseBalance.eq(
+        mm0BalsBefore.baseBalance.add(new anchor.BN(100))
+      )
+    );
+  });
+
+  it("Keeps limit orders on the lot sizes", async () => {
+    const {
+      orderBook,
+      makers: [[mm0], [mm1]],
+    } = await createMarket();
+
+    await program.methods
+      .updateOrderBook(
+        250, // max observation change per update
+        100, // max observation change per slot
+        new anchor.BN(1), // min base limit amount
+        new anchor.BN(1), // min quote limit amount
+        new anchor.BN(1), // tick size
+        new anchor.BN(10), // base lot size
+        new anchor.BN(1), // quote lot size
+        0, // price band
+        null // taker fee override
+      )
+      .accounts({
+        globalState,
+        admin: admin.publicKey,
+        orderBook,
+      })
+      .signers([admin])
+      .rpc();
+
+    await assertFailsWith(
+      program.methods
+        .submitLimitOrder(
+          { sell: {} },
+          new anchor.BN(15), // amount
+          new anchor.BN(1e9), // price
+          0, // ref id
+          1, // mm index
+          null, // expiry slot
+          null, // peg offset
+          { limit: {} }, // order type
+          null // hidden amount
+        )
+        .accounts({
+          authority: mm1.publicKey,
+          orderBook,
+          globalState,
+        })
+        .signers([mm1])
+        .rpc(),
+      "AmountNotOnLot"
+    );
+
+    // buys offer quote, which is on a lot size of 1
+    await program.methods
+      .submitLimitOrder(
+        { buy: {} },
+        new anchor.BN(105), // amount
+        new anchor.BN(1e9), // price
+        1, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    const mm1BalsBefore = await program.methods
+      .getMarketMakerBalances(mm1.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+
+    // 129 is left after the taker fee, of which the buy absorbs 105. Only
+    // 20 of the other 24 is on the lot size, so the last 4 stay with mm1.
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(130), // amount
+        new anchor.BN(1e9), // price
+        2, // ref id
+        1, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm1.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm1])
+      .rpc();
+
+    const mm1BalsAfter = await program.methods
+      .getMarketMakerBalances(mm1.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert(
+      mm1BalsAfter.baseBalance.eq(
+        mm1BalsBefore.baseBalance.sub(new anchor.BN(126))
+      )
+    );
+    assert(
+      mm1BalsAfter.quoteBalance.eq(
+        mm1BalsBefore.quoteBalance.add(new anchor.BN(105))
+      )
+    );
+
+    const sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 1);
+    assert(sells[0].amount.eq(new anchor.BN(20)));
+  });
+
+  it("Rejects limit orders priced outside the band", async () => {
+    const {
+      orderBook,
+      makers: [[mm0], [mm1]],
+    } = await createMarket();
+
+    await program.methods
+      .updateOrderBook(
+        250, // max observation change per update
+        100, // max observation change per slot
+        new anchor.BN(1), // min base limit amount
+        new anchor.BN(1), // min quote limit amount
+        new anchor.BN(1), // tick size
+        new anchor.BN(1), // base lot size
+        new anchor.BN(1), // quote lot size
+        1000, // price band
+        null // taker fee override
+      )
+      .accounts({
+        globalState,
+        admin: admin.publicKey,
+        orderBook,
+      })
+      .signers([admin])
+      .rpc();
+
+    const submit = (
+      mm: anchor.web3.Keypair,
+      mmIndex: number,
+      side: any,
+      price: number,
+      refId: number
+    ) =>
+      program.methods
+        .submitLimitOrder(
+          side,
+          new anchor.BN(100), // amount
+          new anchor.BN(price),
+          refId,
+          mmIndex,
+          null, // expiry slot
+          null, // peg offset
+          { limit: {} }, // order type
+          null // hidden amount
+        )
+        .accounts({
+          authority: mm.publicKey,
+          orderBook,
+          globalState,
+        })
+        .signers([mm])
+        .rpc();
+
+    // without an observation there is nothing to band prices around
+    await submit(mm0, 0, { buy: {} }, 0.5e9, 0);
+    await submit(mm1, 1, { sell: {} }, 1.5e9, 1);
+
+    // the first observation is the midpoint of 1, so prices between 0.9 and
+    // 1.1 are allowed from here on
+    await submit(mm0, 0, { buy: {} }, 0.9e9, 2);
+    await submit(mm1, 1, { sell: {} }, 1.1e9, 3);
+
+    await assertFailsWith(
+      submit(mm0, 0, { buy: {} }, 0.89e9, 4),
+      "PriceOutsideBand"
+    );
+    await assertFailsWith(
+      submit(mm1, 1, { sell: {} }, 1.11e9, 5),
+      "PriceOutsideBand"
+    );
+  });
+
+  it("Only charges partial takes for what they fill", async () => {
+    const {
+      orderBook,
+      baseVault,
+      quoteVault,
+      makers: [[mm0], [mm1, mm1Base, mm1Quote]],
+    } = await createMarket();
+
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(1000), // amount
+        new anchor.BN(1e9), // price
+        0, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    const take = program.methods
+      .submitTakeOrder(
+        { buy: {} },
+        new anchor.BN(10000),
+        new anchor.BN(0),
+        null, // limit price
+        { abortTake: {} }, // self-trade behavior
+        false // fill or kill
+      )
+      .accounts({
+        globalState,
+        userBaseAccount: mm1Base,
+        userQuoteAccount: mm1Quote,
+        baseVault,
+        quoteVault,
+        authority: mm1.publicKey,
+        orderBook,
+        tokenProgram: token.TOKEN_PROGRAM_ID,
+        referrer: null,
+      })
+      .signers([mm1]);
+
+    // 9990 is left after the taker fee of 10, of which the book only fills
+    // 1000. Only 8 of the fee is refunded, since refunds are rounded down.
+    const { raw } = await take.simulate();
+    const returnLog = raw.find((log) =>
+      log.startsWith(`Program return: ${program.programId} `)
+    );
+    const receipt = Buffer.from(returnLog.split(" ")[3], "base64");
+    assert.equal(receipt.readBigUInt64LE(0), BigInt(1002)); // amount in filled
+    assert.equal(receipt.readBigUInt64LE(8), BigInt(8998)); // amount in unfilled
+    assert.equal(receipt.readBigUInt64LE(16), BigInt(1000)); // amount out
+
+    const mm1BaseBefore = (await token.getAccount(connection, mm1Base)).amount;
+    const mm1QuoteBefore = (await token.getAccount(connection, mm1Quote))
+      .amount;
+
+    await take.rpc();
+
+    assert.equal(
+      Number((await token.getAccount(connection, mm1Base)).amount),
+      Number(mm1BaseBefore) + 1000
+    );
+    assert.equal(
+      Number((await token.getAccount(connection, mm1Quote)).amount),
+      Number(mm1QuoteBefore) - 1002
+    );
+
+    const ob = await program.account.orderBook.fetch(orderBook);
+    assert(ob.quoteFeesSweepable.eq(new anchor.BN(2)));
+  });
+
+  it("Fails fill-or-kill takes that run out of book", async () => {
+    const {
+      orderBook,
+      baseVault,
+      quoteVault,
+      makers: [[mm0], [mm1, mm1Base, mm1Quote]],
+    } = await createMarket();
+
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(100), // amount
+        new anchor.BN(1e9), // price
+        0, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    const take = (amountIn: number) =>
+      program.methods
+        .submitTakeOrder(
+          { buy: {} },
+          new anchor.BN(amountIn),
+          new anchor.BN(0),
+          null, // limit price
+          { abortTake: {} }, // self-trade behavior
+          true // fill or kill
+        )
+        .accounts({
+          globalState,
+          userBaseAccount: mm1Base,
+          userQuoteAccount: mm1Quote,
+          baseVault,
+          quoteVault,
+          authority: mm1.publicKey,
+          orderBook,
+          tokenProgram: token.TOKEN_PROGRAM_ID,
+          referrer: null,
+        })
+        .signers([mm1])
+        .rpc();
+
+    // the book only has 100 to sell, which 1000 would run through
+    await assertFailsWith(take(1000), "FillOrKillNotFilled");
+
+    const sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 1);
+    assert(sells[0].amount.eq(new anchor.BN(100)));
+
+    // 99 is left after the taker fee, which the book can fill
+    await take(100);
+  });
+
+  it("Refills iceberg orders behind their price level", async () => {
+    const {
+      mintAuthority,
+      base,
+      quote,
+      orderBook,
+      baseVault,
+      quoteVault,
+      makers: [[mm0], [mm1]],
+    } = await createMarket();
+
+    // shows 100 at a time of 300
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(100), // amount
+        new anchor.BN(1e9), // price
+        0, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        new anchor.BN(200) // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(100), // amount
+        new anchor.BN(1e9), // price
+        1, // ref id
+        1, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm1.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm1])
+      .rpc();
+
+    const quoteBalances = async () =>
+      Promise.all(
+        [mm0, mm1].map(
+          async (mm) =>
+            (
+              await program.methods
+                .getMarketMakerBalances(mm.publicKey)
+                .accounts({
+                  orderBook,
+                })
+                .view()
+            ).quoteBalance
+        )
+      );
+
+    const taker = anchor.web3.Keypair.generate();
+    const takerBase = await token.createAccount(
+      connection,
+      payer,
+      base,
+      taker.publicKey
+    );
+    const takerQuote = await token.createAccount(
+      connection,
+      payer,
+      quote,
+      taker.publicKey
+    );
+    await token.mintTo(
+      connection,
+      payer,
+      quote,
+      takerQuote,
+      mintAuthority,
+      QUOTE_AMOUNT
+    );
+
+    // 100 is left after the taker fee, which is exactly one slice
+    const take = () =>
+      program.methods
+        .submitTakeOrder(
+          { buy: {} },
+          new anchor.BN(101),
+          new anchor.BN(0),
+          null, // limit price
+          { abortTake: {} }, // self-trade behavior
+          false // fill or kill
+        )
+        .accounts({
+          globalState,
+          userBaseAccount: takerBase,
+          userQuoteAccount: takerQuote,
+          baseVault,
+          quoteVault,
+          authority: taker.publicKey,
+          orderBook,
+          tokenProgram: token.TOKEN_PROGRAM_ID,
+          referrer: null,
+        })
+        .signers([taker])
+        .rpc();
+
+    let [mm0QuoteBefore, mm1QuoteBefore] = await quoteBalances();
+    await take();
+    let [mm0QuoteAfter, mm1QuoteAfter] = await quoteBalances();
+    assert(mm0QuoteAfter.eq(mm0QuoteBefore.add(new anchor.BN(100))));
+    assert(mm1QuoteAfter.eq(mm1QuoteBefore));
+
+    // mm0's order is refilled with another slice of 100
+    let sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 2);
+    assert(sells[0].amount.eq(new anchor.BN(100)));
+    assert(sells[1].amount.eq(new anchor.BN(100)));
+
+    // and now comes after mm1's, which was placed after it
+    [mm0QuoteBefore, mm1QuoteBefore] = [mm0QuoteAfter, mm1QuoteAfter];
+    await take();
+    [mm0QuoteAfter, mm1QuoteAfter] = await quoteBalances();
+    assert(mm0QuoteAfter.eq(mm0QuoteBefore));
+    assert(mm1QuoteAfter.eq(mm1QuoteBefore.add(new anchor.BN(100))));
+
+    sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 1);
+    assert(sells[0].amount.eq(new anchor.BN(100)));
+  });
+
+  it("Triggers stop orders once the TWAP reaches them", async () => {
+    const {
+      orderBook,
+      makers: [[mm0], [mm1]],
+    } = await createMarket();
+
+    const balances = (mm: anchor.web3.Keypair) =>
+      program.methods
+        .getMarketMakerBalances(mm.publicKey)
+        .accounts({
+          orderBook,
+        })
+        .view();
+
+    // a buy at 0.9 and a sell at 1.1 put the first observation at 1
+    await program.methods
+      .submitLimitOrder(
+        { buy: {} },
+        new anchor.BN(100), // amount
+        new anchor.BN(0.9e9), // price
+        0, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(100), // amount
+        new anchor.BN(1.1e9), // price
+        1, // ref id
+        1, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm1.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm1])
+      .rpc();
+
+    // Stop orders trigger at the start of the next instruction, so each of
+    // these triggers the one submitted before it.
+    const submitStop = (
+      triggerPrice: number,
+      limitPrice: number | null,
+      refId: number,
+      maxSlippageBps: number,
+      mm = mm1,
+      mmIndex = 1
+    ) =>
+      program.methods
+        .submitStopOrder(
+          { sell: {} },
+          { stopLoss: {} },
+          new anchor.BN(triggerPrice),
+          new anchor.BN(50), // amount
+          limitPrice === null ? null : new anchor.BN(limitPrice),
+          refId,
+          mmIndex,
+          maxSlippageBps
+        )
+        .accounts({
+          authority: mm.publicKey,
+          orderBook,
+          globalState,
+        })
+        .signers([mm])
+        .rpc();
+
+    const mm1BalsBefore = await balances(mm1);
+
+    // can't sell for less than 0.95, which the buy at 0.9 is
+    await submitStop(1e9, null, 2, 500);
+
+    // can sell for as little as 0.85, so this fills against the buy
+    await submitStop(1e9, null, 3, 1500);
+
+    let mm1BalsAfter = await balances(mm1);
+    assert(
+      mm1BalsAfter.baseBalance.eq(
+        mm1BalsBefore.baseBalance.sub(new anchor.BN(50))
+      )
+    );
+    assert(mm1BalsAfter.quoteBalance.eq(mm1BalsBefore.quoteBalance));
+
+    const mm0BalsBefore = await balances(mm0);
+
+    // becomes a sell at 1.05 once triggered
+    await submitStop(1e9, 1.05e9, 4, 0);
+
+    // 49 is left after the taker fee, which sells for 44 at 0.9
+    mm1BalsAfter = await balances(mm1);
+    assert(
+      mm1BalsAfter.baseBalance.eq(
+        mm1BalsBefore.baseBalance.sub(new anchor.BN(100))
+      )
+    );
+    assert(
+      mm1BalsAfter.quoteBalance.eq(
+        mm1BalsBefore.quoteBalance.add(new anchor.BN(44))
+      )
+    );
+    const mm0BalsAfter = await balances(mm0);
+    assert(
+      mm0BalsAfter.baseBalance.eq(
+        mm0BalsBefore.baseBalance.add(new anchor.BN(49))
+      )
+    );
+
+    const buys = await program.methods
+      .getBestOrders({ buy: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(buys.length, 1);
+    assert(buys[0].amount.eq(new anchor.BN(56)));
+
+    // these never trigger, and triggering the sell at 1.05 frees up a slot
+    for (let i = 0; i < 4; i++) {
+      await submitStop(1, null, 5 + i, 0);
+    }
+
+    const sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 2);
+    assert(sells[0].amount.eq(new anchor.BN(50)));
+    assert(sells[0].price.eq(new anchor.BN(1.05e9)));
+
+    // a maker can only have so many stop orders, which leaves room for others
+    await assertFailsWith(submitStop(1, null, 9, 0), "TooManyStopOrders");
+    await submitStop(1, null, 10, 0, mm0, 0);
+  });
+
+  it("Pays makers a rebate out of the taker fee", async () => {
+    const {
+      orderBook,
+      baseVault,
+      quoteVault,
+      makers: [[mm0], [mm1, mm1Base, mm1Quote]],
+    } = await createMarket();
+
+    await program.methods
+      .updateGlobalState(
+        null, // admin
+        30, // taker fee
+        null, // market maker burn
+        10, // maker rebate
+        null, // referral share
+        null, // market maker cooldown
+        null // market maker deposit mode
+      )
+      .accounts({
+        globalState,
+        admin: admin.publicKey,
+      })
+      .signers([admin])
+      .rpc();
+
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(200_000), // amount
+        new anchor.BN(1e9), // price
+        0, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    const mm0BalsBefore = await program.methods
+      .getMarketMakerBalances(mm0.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+
+    await program.methods
+      .submitTakeOrder(
+        { buy: {} },
+        new anchor.BN(100_000),
+        new anchor.BN(0),
+        null, // limit price
+        { abortTake: {} }, // self-trade behavior
+        false // fill or kill
+      )
+      .accounts({
+        globalState,
+        userBaseAccount: mm1Base,
+        userQuoteAccount: mm1Quote,
+        baseVault,
+        quoteVault,
+        authority: mm1.publicKey,
+        orderBook,
+        tokenProgram: token.TOKEN_PROGRAM_ID,
+        referrer: null,
+      })
+      .signers([mm1])
+      .rpc();
+
+    // the maker is paid the 99,700 left after the fee of 300, plus a rebate of
+    // 99, which leaves 201 of the fee to sweep
+    const mm0BalsAfter = await program.methods
+      .getMarketMakerBalances(mm0.publicKey)
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert(
+      mm0BalsAfter.quoteBalance.eq(
+        mm0BalsBefore.quoteBalance.add(new anchor.BN(99_799))
+      )
+    );
+
+    const ob = await program.account.orderBook.fetch(orderBook);
+    assert(ob.quoteFeesSweepable.eq(new anchor.BN(201)));
+
+    // the other tests expect the default fees
+    await program.methods
+      .updateGlobalState(null, 10, null, 0, null, null, null)
+      .accounts({
+        globalState,
+        admin: admin.publicKey,
+      })
+      .signers([admin])
+      .rpc();
+  });
+
+  it("Charges an order book's taker fee override", async () => {
+    const {
+      orderBook,
+      baseVault,
+      quoteVault,
+      makers: [[mm0], [mm1, mm1Base, mm1Quote]],
+    } = await createMarket();
+
+    await program.methods
+      .updateOrderBook(
+        250, // max observation change per update
+        100, // max observation change per slot
+        new anchor.BN(1), // min base limit amount
+        new anchor.BN(1), // min quote limit amount
+        new anchor.BN(1), // tick size
+        new anchor.BN(1), // base lot size
+        new anchor.BN(1), // quote lot size
+        0, // price band
+        50 // taker fee override
+      )
+      .accounts({
+        globalState,
+        admin: admin.publicKey,
+        orderBook,
+      })
+      .signers([admin])
+      .rpc();
+
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(200_000), // amount
+        new anchor.BN(1e9), // price
+        0, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    const mm1BaseBefore = (await token.getAccount(connection, mm1Base)).amount;
+
+    await program.methods
+      .submitTakeOrder(
+        { buy: {} },
+        new anchor.BN(100_000),
+        new anchor.BN(0),
+        null, // limit price
+        { abortTake: {} }, // self-trade behavior
+        false // fill or kill
+      )
+      .accounts({
+        globalState,
+        userBaseAccount: mm1Base,
+        userQuoteAccount: mm1Quote,
+        baseVault,
+        quoteVault,
+        authority: mm1.publicKey,
+        orderBook,
+        tokenProgram: token.TOKEN_PROGRAM_ID,
+        referrer: null,
+      })
+      .signers([mm1])
+      .rpc();
+
+    // 0.5% rather than the global 0.1%
+    assert.equal(
+      Number((await token.getAccount(connection, mm1Base)).amount),
+      Number(mm1BaseBefore) + 99_500
+    );
+
+    const ob = await program.account.orderBook.fetch(orderBook);
+    assert(ob.quoteFeesSweepable.eq(new anchor.BN(500)));
+  });
+
+  it("Pays referrers a share of the taker fee", async () => {
+    const {
+      quote,
+      orderBook,
+      baseVault,
+      quoteVault,
+      makers: [[mm0], [mm1, mm1Base, mm1Quote]],
+    } = await createMarket();
+
+    await program.methods
+      .updateGlobalState(
+        null, // admin
+        null, // taker fee
+        null, // market maker burn
+        null, // maker rebate
+        2500, // referral share
+        null, // market maker cooldown
+        null // market maker deposit mode
+      )
+      .accounts({
+        globalState,
+        admin: admin.publicKey,
+      })
+      .signers([admin])
+      .rpc();
+
+    const referrer = await token.createAccount(
+      connection,
+      payer,
+      quote,
+      anchor.web3.Keypair.generate().publicKey
+    );
+
+    await program.methods
+      .submitLimitOrder(
+        { sell: {} },
+        new anchor.BN(200_000), // amount
+        new anchor.BN(1e9), // price
+        0, // ref id
+        0, // mm index
+        null, // expiry slot
+        null, // peg offset
+        { limit: {} }, // order type
+        null // hidden amount
+      )
+      .accounts({
+        authority: mm0.publicKey,
+        orderBook,
+        globalState,
+      })
+      .signers([mm0])
+      .rpc();
+
+    const mm1QuoteBefore = (await token.getAccount(connection, mm1Quote))
+      .amount;
+
+    await program.methods
+      .submitTakeOrder(
+        { buy: {} },
+        new anchor.BN(100_000),
+        new anchor.BN(0),
+        null, // limit price
+        { abortTake: {} }, // self-trade behavior
+        false // fill or kill
+      )
+      .accounts({
+        globalState,
+        userBaseAccount: mm1Base,
+        userQuoteAccount: mm1Quote,
+        baseVault,
+        quoteVault,
+        authority: mm1.publicKey,
+        orderBook,
+        tokenProgram: token.TOKEN_PROGRAM_ID,
+        referrer,
+      })
+      .signers([mm1])
+      .rpc();
+
+    // the referrer gets 25 of the fee of 100, and the rest can be swept
+    assert.equal(
+      Number((await token.getAccount(connection, mm1Quote)).amount),
+      Number(mm1QuoteBefore) - 100_000
+    );
+    assert.equal(
+      Number((await token.getAccount(connection, referrer)).amount),
+      25
+    );
+
+    const ob = await program.account.orderBook.fetch(orderBook);
+    assert(ob.quoteFeesSweepable.eq(new anchor.BN(75)));
+
+    // the other tests expect no referral share
+    await program.methods
+      .updateGlobalState(null, null, null, null, 0, null, null)
+      .accounts({
+        globalState,
+        admin: admin.publicKey,
+      })
+      .signers([admin])
+      .rpc();
+  });
+
+  it("Makes market makers wait out the cooldown between seats", async () => {
+    const { orderBook } = await createOrderBook(program, connection, payer);
+
+    const setCooldown = (slots: number) =>
+      program.methods
+        .updateGlobalState(
+          null, // admin
+          null, // taker fee
+          null, // market maker burn
+          null, // maker rebate
+          null, // referral share
+          new anchor.BN(slots), // market maker cooldown
+          null // market maker deposit mode
+        )
+        .accounts({
+          globalState,
+          admin: admin.publicKey,
+        })
+        .signers([admin])
+        .rpc();
+
+    const addMarketMaker = (index: number) =>
+      program.methods
+        .addMarketMaker(anchor.web3.Keypair.generate().publicKey, index)
+        .accounts({
+          orderBook,
+          payer: payer.publicKey,
+          globalState,
+          admin: admin.publicKey,
+          incinerator: null,
+        })
+        .rpc();
+
+    await setCooldown(1000);
+
+    // the first seat on an order book has nothing to wait for
+    await addMarketMaker(0);
+    await assertFailsWith(addMarketMaker(1), "MarketMakerCooldown");
+
+    // the other tests expect no cooldown
+    await setCooldown(0);
+
+    await addMarketMaker(1);
+  });
+
+  it("Escrows or burns market maker deposits", async () => {
+    const setDepositMode = (mode: any) =>
+      program.methods
+        .updateGlobalState(
+          null, // admin
+          null, // taker fee
+          null, // market maker burn
+          null, // maker rebate
+          null, // referral share
+          null, // market maker cooldown
+          mode // market maker deposit mode
+        )
+        .accounts({
+          globalState,
+          admin: admin.publicKey,
+        })
+        .signers([admin])
+        .rpc();
+
+    const deposit = (await program.account.globalState.fetch(globalState))
+      .marketMakerBurnInLamports;
+
+    // escrowed deposits are held by the order book until the seat is removed
+    await setDepositMode({ escrow: {} });
+
+    const {
+      orderBook,
+      baseVault,
+      quoteVault,
+      makers: [, [mm1, mm1Base, mm1Quote]],
+    } = await createMarket();
+
+    let ob = await program.account.orderBook.fetch(orderBook);
+    assert(ob.marketMakers[1].depositLamports.eq(deposit));
+
+    const orderBookLamportsBefore = await connection.getBalance(orderBook);
+    const mm1LamportsBefore = await connection.getBalance(mm1.publicKey);
+
+    await program.methods
+      .removeMarketMaker(1)
+      .accounts({
+        orderBook,
+        authority: mm1.publicKey,
+        baseTo: mm1Base,
+        quoteTo: mm1Quote,
+        baseVault,
+        quoteVault,
+        tokenProgram: token.TOKEN_PROGRAM_ID,
+      })
+      .signers([mm1])
+      .rpc();
+
+    assert.equal(
+      await connection.getBalance(orderBook),
+      orderBookLamportsBefore - deposit.toNumber()
+    );
+    assert.equal(
+      await connection.getBalance(mm1.publicKey),
+      mm1LamportsBefore + deposit.toNumber()
+    );
+
+    // burned deposits go to the incinerator, which has to be passed in
+    await setDepositMode({ burn: {} });
+
+    const incinerator = new anchor.web3.PublicKey(
+      "1nc1nerator11111111111111111111111111111111"
+    );
+    const addMarketMaker = (withIncinerator: boolean) =>
+      program.methods
+        .addMarketMaker(anchor.web3.Keypair.generate().publicKey, 1)
+        .accounts({
+          orderBook,
+          payer: payer.publicKey,
+          globalState,
+          admin: admin.publicKey,
+          incinerator: withIncinerator ? incinerator : null,
+        });
+
+    await assertFailsWith(addMarketMaker(false).rpc(), "MissingIncinerator");
+
+    // The runtime burns the incinerator's lamports at the end of each slot,
+    // so its balance can only be checked in a simulation.
+    const incineratorLamportsBefore = await connection.getBalance(incinerator);
+    const simulation = await connection.simulateTransaction(
+      await addMarketMaker(true).transaction(),
+      [payer],
+      [incinerator]
+    );
+    assert.isNull(simulation.value.err);
+    assert.equal(
+      simulation.value.accounts[0].lamports,
+      incineratorLamportsBefore + deposit.toNumber()
+    );
+
+    const adminLamportsBefore = await connection.getBalance(admin.publicKey);
+    await addMarketMaker(true).rpc();
+    assert.equal(
+      await connection.getBalance(admin.publicKey),
+      adminLamportsBefore
+    );
+
+    ob = await program.account.orderBook.fetch(orderBook);
+    assert(ob.marketMakers[1].depositLamports.eqn(0));
+
+    // the other tests expect deposits to go to the admin
+    await setDepositMode({ payAdmin: {} });
+  });
+
+  it("Drops limit orders once they expire", async () => {
+    const {
+      orderBook,
+      baseVault,
+      quoteVault,
+      makers: [[mm0], [mm1, mm1Base, mm1Quote]],
+    } = await createMarket();
+
+    const submitSell = (
+      price: number,
+      refId: number,
+      expirySlot: number | null
+    ) =>
+      program.methods
+        .submitLimitOrder(
+          { sell: {} },
+          new anchor.BN(100), // amount
+          new anchor.BN(price),
+          refId,
+          0, // mm index
+          expirySlot === null ? null : new anchor.BN(expirySlot),
+          null, // peg offset
+          { limit: {} }, // order type
+          null // hidden amount
+        )
+        .accounts({
+          authority: mm0.publicKey,
+          orderBook,
+          globalState,
+        })
+        .signers([mm0])
+        .rpc();
+
+    const balances = () =>
+      program.methods
+        .getMarketMakerBalances(mm0.publicKey)
+        .accounts({
+          orderBook,
+        })
+        .view();
+
+    const slot = await connection.getSlot();
+    await assertFailsWith(submitSell(1e9, 0, slot - 1), "OrderAlreadyExpired");
+
+    const mm0BalsBefore = await balances();
+
+    // only fillable for the next few slots
+    const expirySlot = (await connection.getSlot()) + 10;
+    await submitSell(1e9, 1, expirySlot);
+    await submitSell(1.1e9, 2, null);
+
+    let sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 2);
+
+    await waitForSlot(connection, expirySlot + 1);
+
+    // the getters skip the expired order even though it is still stored
+    sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 1);
+    assert(sells[0].price.eq(new anchor.BN(1.1e9)));
+
+    const orderIndex = await program.methods
+      .getOrderIndex({ sell: {} }, 1, 0)
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.isNull(orderIndex);
+
+    let mm0BalsAfter = await balances();
+    assert(
+      mm0BalsAfter.baseBalance.eq(
+        mm0BalsBefore.baseBalance.sub(new anchor.BN(200))
+      )
+    );
+
+    // the next instruction deletes it and credits mm0, so this take fills
+    // against the order at 1.1 instead: 99 is left after the taker fee
+    await program.methods
+      .submitTakeOrder(
+        { buy: {} },
+        new anchor.BN(100),
+        new anchor.BN(0),
+        null, // limit price
+        { abortTake: {} }, // self-trade behavior
+        false // fill or kill
+      )
+      .accounts({
+        globalState,
+        userBaseAccount: mm1Base,
+        userQuoteAccount: mm1Quote,
+        baseVault,
+        quoteVault,
+        authority: mm1.publicKey,
+        orderBook,
+        tokenProgram: token.TOKEN_PROGRAM_ID,
+        referrer: null,
+      })
+      .signers([mm1])
+      .rpc();
+
+    mm0BalsAfter = await balances();
+    assert(
+      mm0BalsAfter.baseBalance.eq(
+        mm0BalsBefore.baseBalance.sub(new anchor.BN(100))
+      )
+    );
+    assert(
+      mm0BalsAfter.quoteBalance.eq(
+        mm0BalsBefore.quoteBalance.add(new anchor.BN(99))
+      )
+    );
+
+    const ob = await program.account.orderBook.fetch(orderBook);
+    assert.equal(ob.sells.orders[ob.sells.bestOrderIdx].refId, 2);
+  });
+
+  it("Moves updated limit orders in the queue", async () => {
+    const {
+      orderBook,
+      makers: [[mm0], [mm1]],
+    } = await createMarket();
+
+    const submit = (
+      side: any,
+      mm: anchor.web3.Keypair,
+      mmIndex: number,
+      amount: number,
+      price: number,
+      refId: number,
+      pegOffsetBps: number | null = null
+    ) =>
+      program.methods
+        .submitLimitOrder(
+          side,
+          new anchor.BN(amount),
+          new anchor.BN(price),
+          refId,
+          mmIndex,
+          null, // expiry slot
+          pegOffsetBps,
+          { limit: {} }, // order type
+          null // hidden amount
+        )
+        .accounts({
+          authority: mm.publicKey,
+          orderBook,
+          globalState,
+        })
+        .signers([mm])
+        .rpc();
+
+    const update = async (
+      refId: number,
+      newAmount: number | null,
+      newPrice: number | null
+    ) => {
+      const orderIndex = await program.methods
+        .getOrderIndex({ buy: {} }, refId, 0)
+        .accounts({
+          orderBook,
+        })
+        .view();
+
+      await program.methods
+        .updateLimitOrder(
+          { buy: {} },
+          orderIndex,
+          0, // mm index
+          newAmount === null ? null : new anchor.BN(newAmount),
+          newPrice === null ? null : new anchor.BN(newPrice)
+        )
+        .accounts({
+          orderBook,
+          authority: mm0.publicKey,
+        })
+        .signers([mm0])
+        .rpc();
+    };
+
+    const assertBuys = async (expected: [number, number][]) => {
+      const buys = await program.methods
+        .getBestOrders({ buy: {} })
+        .accounts({
+          orderBook,
+        })
+        .view();
+      assert.deepEqual(
+        buys.map((buy) => [buy.amount.toNumber(), buy.price.toNumber()]),
+        expected
+      );
+    };
+
+    await submit({ buy: {} }, mm0, 0, 100, 1e9, 0);
+    await submit({ buy: {} }, mm1, 1, 101, 1e9, 1);
+    await submit({ buy: {} }, mm0, 0, 102, 0.9e9, 2);
+
+    // growing an order sends it to the back of its price level
+    await update(0, 150, null);
+    await assertBuys([
+      [101, 1e9],
+      [150, 1e9],
+      [102, 0.9e9],
+    ]);
+
+    // and repricing it moves it to its new one
+    await update(2, null, 1.1e9);
+    await assertBuys([
+      [102, 1.1e9],
+      [101, 1e9],
+      [150, 1e9],
+    ]);
+
+    await submit({ sell: {} }, mm1, 1, 100, 1.2e9, 3);
+    await assertFailsWith(update(2, null, 1.2e9), "UpdateWouldCrossBook");
+
+    // pegged orders follow the TWAP, so they can't be given a price
+    await submit({ buy: {} }, mm0, 0, 100, 0, 4, -1000);
+    await assertFailsWith(update(4, null, 1e9), "CannotRepricePeggedOrder");
+  });
+
+  it("Rejects post-only orders that would cross", async () => {
+    const {
+      orderBook,
+      makers: [[mm0], [mm1]],
+    } = await createMarket();
+
+    const submit = (
+      side: any,
+      mm: anchor.web3.Keypair,
+      mmIndex: number,
+      price: number,
+      refId: number,
+      orderType: any
+    ) =>
+      program.methods
+        .submitLimitOrder(
+          side,
+          new anchor.BN(100), // amount
+          new anchor.BN(price),
+          refId,
+          mmIndex,
+          null, // expiry slot
+          null, // peg offset
+          orderType,
+          null // hidden amount
+        )
+        .accounts({
+          authority: mm.publicKey,
+          orderBook,
+          globalState,
+        })
+        .signers([mm])
+        .rpc();
+
+    // a sell at a price of a single tick leaves no room to slide a buy behind
+    await submit({ sell: {} }, mm1, 1, 1, 0, { limit: {} });
+
+    await assertFailsWith(
+      submit({ buy: {} }, mm0, 0, 2, 1, { postOnly: {} }),
+      "PostOnlyWouldCross"
+    );
+    await assertFailsWith(
+      submit({ buy: {} }, mm0, 0, 2, 2, { postOnlySlide: {} }),
+      "PostOnlyWouldCross"
+    );
+
+    // neither should have touched the sell
+    const sells = await program.methods
+      .getBestOrders({ sell: {} })
+      .accounts({
+        orderBook,
+      })
+      .view();
+    assert.equal(sells.length, 1);
+    assert(sells[0].amount.eq(new anchor.BN(100)));
+  });
+});
+
+const BASE_AMOUNT = 1_000_000_000;
+const QUOTE_AMOUNT = 1_000_000_000;
+
+async function createOrderBook(
+  program: Program<Clob>,
+  connection: anchor.web3.Connection,
+  payer: anchor.web3.Keypair
+) {
+  const mintAuthority = anchor.web3.Keypair.generate();
+  const quote = await token.createMint(
+    connection,
+    payer,
+    mintAuthority.publicKey,
+    mintAuthority.publicKey,
+    8
+  );
+  const base = await token.createMint(
+    connection,
+    payer,
+    mintAuthority.publicKey,
+    mintAuthority.publicKey,
+    8
+  );
+
+  const [orderBook] = anchor.web3.PublicKey.findProgramAddressSync(
+    [
+      anchor.utils.bytes.utf8.encode("order_book"),
+      base.toBuffer(),
+      quote.toBuffer(),
+    ],
+    program.programId
+  );
+
+  const baseVault = await token.getAssociatedTokenAddress(
+    base,
+    orderBook,
+    true
+  );
+
+  const quoteVault = await token.getAssociatedTokenAddress(
+    quote,
+    orderBook,
+    true
+  );
+
+  // the order book is too large to allocate in one instruction
+  const allocations = Math.ceil(program.account.orderBook.size / 10240);
+  for (let i = 0; i < allocations; i++) {
+    await program.methods
+      .allocateOrderBook()
+      .accounts({
+        orderBook,
+        payer: payer.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        base,
+        quote,
+      })
+      .rpc();
+  }
+
+  await program.methods
+    .initializeOrderBook()
+    .accounts({
+      orderBook,
+      payer: payer.publicKey,
+      systemProgram: anchor.web3.SystemProgram.programId,
+      base,
+      quote,
+      baseVault,
+      quoteVault,
+    })
+    .rpc();
+
+  return { mintAuthority, base, quote, orderBook, baseVault, quoteVault };
+}
+
+// Fails unless `tx` is rejected with the program error `code`
+async function assertFailsWith(tx: Promise<unknown>, code: string) {
+  try {
+    await tx;
+  } catch (e) {
+    assert.equal(e.error?.errorCode?.code, code);
+    return;
+  }
+  assert.fail(`expected the transaction to fail with ${code}`);
+}
+
+async function waitForSlot(connection: anchor.web3.Connection, slot: number) {
+  while ((await connection.getSlot()) < slot) {
+    await new Promise((resolve) => setTimeout(resolve, 100));
+  }
+}
 
 async function generateMarketMaker(
   index: number,
@@ -557,6 +3189,7 @@ async function generateMarketMaker(
       payer: payer.publicKey,
       globalState,
       admin: admin.publicKey,
+      incinerator: null,
     })
     .rpc();
 